@@ -0,0 +1,65 @@
+//  Measures how long it takes for a filesystem event to
+//  reach a consumer of the event stream after some idle time.
+//
+//  cargo bench --bench latency
+
+use std::fs;
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+use watcher_cli::watcher;
+
+const ROUNDS: usize = 32;
+const IDLE: Duration = Duration::from_millis(300);
+
+#[tokio::main]
+async fn main() {
+    let dir = std::env::temp_dir().join(format!("watcher-cli-latency-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut stream = watcher::watch(dir.clone());
+
+    stream.ready().await;
+
+    let mut latencies = Vec::<Duration>::with_capacity(ROUNDS);
+
+    for round in 0..ROUNDS {
+        let path = dir.join(format!("{}", round));
+
+        // The consumer is already waiting on the stream
+        // when the event happens, like the cli would be.
+        let writer = tokio::spawn({
+            let path = path.clone();
+            async move {
+                tokio::time::sleep(IDLE).await;
+                let start = Instant::now();
+                fs::write(&path, b"").unwrap();
+                start
+            }
+        });
+
+        while let Some(event) = stream.next().await {
+            if *event.unwrap().path == *path {
+                break;
+            }
+        }
+
+        latencies.push(writer.await.unwrap().elapsed());
+    }
+
+    stream.stop();
+    let _ = fs::remove_dir_all(&dir);
+
+    latencies.sort();
+
+    let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q) as usize];
+
+    println!(
+        "latency after {} ms idle, over {} rounds: min {:?} / p50 {:?} / p90 {:?} / max {:?}",
+        IDLE.as_millis(),
+        latencies.len(),
+        at(0.0),
+        at(0.5),
+        at(0.9),
+        at(1.0),
+    );
+}
@@ -0,0 +1,891 @@
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Child;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+use tokio::task;
+use tokio::task::JoinHandle;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
+use watcher_cli::watcher;
+
+/// A shell command line, with placeholders quoted for the shell,
+/// or a program and its arguments, with no shell in between.
+#[derive(Clone)]
+pub enum Program {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+/// What to run for an event, and where.
+#[derive(Clone)]
+pub struct Command {
+    pub program: Program,
+    pub cwd: Option<PathBuf>,
+    /// Set after the event's own variables, so these win.
+    pub env: Vec<(String, String)>,
+}
+
+// KEY=VAL, for --exec-env.
+pub fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("not KEY=VAL: {}", s)),
+    }
+}
+
+// The event, for commands which would rather not parse
+// it out of their arguments: WATCHER_PATH, WATCHER_WHAT, and so on.
+// WATCHER_RELATIVE_PATH is the path below the root, and
+// WATCHER_TO is only there for renames with both sides.
+fn env_of(event: &watcher::Event) -> Vec<(OsString, OsString)> {
+    let relative = event.path.strip_prefix(&event.root).unwrap_or(&event.path);
+    let mut env = vec![
+        ("WATCHER_PATH".into(), event.path.as_os_str().to_owned()),
+        ("WATCHER_ROOT".into(), event.root.as_os_str().to_owned()),
+        (
+            "WATCHER_RELATIVE_PATH".into(),
+            relative.as_os_str().to_owned(),
+        ),
+        ("WATCHER_WHAT".into(), event.what.to_string().into()),
+        ("WATCHER_KIND".into(), event.kind.to_string().into()),
+        (
+            "WATCHER_WHEN".into(),
+            event.when.as_nanos().to_string().into(),
+        ),
+    ];
+    if let Some(to) = &event.associated {
+        env.push(("WATCHER_TO".into(), to.as_os_str().to_owned()));
+    }
+    env
+}
+
+// Single quotes keep everything as-is, except for single quotes,
+// which end the quoting, are escaped, and start it again.
+fn quote(s: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(s.len() + 2);
+    quoted.push(b'\'');
+    for &c in s {
+        match c {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+// What a placeholder stands for. Paths are passed on
+// byte for byte, whether or not they're UTF-8.
+fn placeholder(name: &str, event: &watcher::Event) -> Option<Vec<u8>> {
+    let path = |path: &Path| path.as_os_str().as_bytes().to_vec();
+    match name {
+        "event" => Some(event.to_string().into_bytes()),
+        "path" | "from" => Some(path(&event.path)),
+        "root" => Some(path(&event.root)),
+        "to" => Some(event.associated.as_deref().map(path).unwrap_or_default()),
+        "what" => Some(event.what.to_string().into_bytes()),
+        "kind" => Some(event.kind.to_string().into_bytes()),
+        "when" => Some(event.when.as_nanos().to_string().into_bytes()),
+        _ => None,
+    }
+}
+
+// {paths} is every path, each quoted on its own, with spaces between.
+fn joined(paths: &[&Path], quoted: bool) -> Vec<u8> {
+    let mut joined = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            joined.push(b' ');
+        }
+        match quoted {
+            true => joined.extend(quote(path.as_os_str().as_bytes())),
+            false => joined.extend_from_slice(path.as_os_str().as_bytes()),
+        }
+    }
+    joined
+}
+
+// Placeholders are replaced in one pass, so a path
+// with "{what}" in its name is left as it is.
+fn expand(template: &str, event: &watcher::Event, paths: &[&Path], quoted: bool) -> Vec<u8> {
+    let mut expanded = Vec::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        expanded.extend_from_slice(&rest.as_bytes()[..open]);
+        rest = &rest[open..];
+        let value = rest.find('}').and_then(|close| match &rest[1..close] {
+            "paths" => Some((close, joined(paths, quoted))),
+            name => Some((
+                close,
+                placeholder(name, event).map(|value| match quoted {
+                    true => quote(&value),
+                    false => value,
+                })?,
+            )),
+        });
+        match value {
+            Some((close, value)) => {
+                expanded.extend(value);
+                rest = &rest[close + 1..];
+            }
+            None => {
+                expanded.push(b'{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.extend_from_slice(rest.as_bytes());
+    expanded
+}
+
+// An event's paths: both sides, for a rename.
+fn paths_of(event: &watcher::Event) -> impl Iterator<Item = &Path> {
+    std::iter::once(&*event.path).chain(event.associated.as_deref())
+}
+
+fn spawn(mut process: tokio::process::Command) -> Option<Child> {
+    match process.spawn() {
+        Ok(child) => Some(child),
+        Err(error) => {
+            eprintln!("e/self/exec : {}", error);
+            None
+        }
+    }
+}
+
+//  Until we're interrupted, which might be never.
+async fn interrupted(interrupt_rx: &mut watch::Receiver<bool>) {
+    if interrupt_rx.wait_for(|is| *is).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+//  Waits for the child to be done. If we're interrupted first,
+//  it's asked to stop, and we wait for it to.
+async fn wait(child: &mut Child, interrupt_rx: &mut watch::Receiver<bool>) {
+    tokio::select! {
+        _ = child.wait() => {}
+        _ = interrupted(interrupt_rx) => {
+            if let Some(pid) = child.id() {
+                unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+            }
+            let _ = child.wait().await;
+        }
+    }
+}
+
+impl Command {
+    //  An argument which is just {paths} is one argument for each path.
+    fn to_process(
+        &self,
+        event: &watcher::Event,
+        paths: &[&Path],
+    ) -> Option<tokio::process::Command> {
+        let mut process = match &self.program {
+            Program::Shell(template) => {
+                let line = OsString::from_vec(expand(template, event, paths, true));
+                let mut process = tokio::process::Command::new("/bin/sh");
+                process.arg("-c").arg(line);
+                process
+            }
+            Program::Argv(argv) => {
+                let (program, args) = argv.split_first()?;
+                let mut process = tokio::process::Command::new(program);
+                for arg in args {
+                    match arg.as_str() {
+                        "{paths}" => process.args(paths),
+                        arg => process.arg(OsStr::from_bytes(&expand(arg, event, paths, false))),
+                    };
+                }
+                process
+            }
+        };
+        process.envs(env_of(event)).envs(self.env.iter().cloned());
+        if let Some(cwd) = &self.cwd {
+            process.current_dir(cwd);
+        }
+        Some(process)
+    }
+
+    fn spawn(&self, event: &watcher::Event, own_group: bool) -> Option<Child> {
+        let paths: Vec<&Path> = paths_of(event).collect();
+        let mut process = self.to_process(event, &paths)?;
+        if own_group {
+            process.process_group(0);
+        }
+        spawn(process)
+    }
+}
+
+/// What to do with an event which can't be run for yet,
+/// because there's no room, or its path's run isn't done.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OnBusy {
+    /// Run for it once there's room, in the order it came
+    Queue,
+    /// Leave it out
+    Drop,
+    /// Run once more for its paths when there's room, for the last event
+    /// on them which had to wait
+    Coalesce,
+}
+
+/// How many runs can go at once, and what to do when that's too many.
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub concurrency: Option<usize>,
+    pub on_busy: OnBusy,
+}
+
+//  The runs going now, and the paths they're for.
+//  No two runs for the same path overlap.
+struct Running {
+    children: JoinSet<Vec<Box<Path>>>,
+    paths: HashSet<Box<Path>>,
+    interrupt_rx: watch::Receiver<bool>,
+}
+
+impl Running {
+    fn can_start(&self, event: &watcher::Event, limit: Limit) -> bool {
+        let has_room = limit.concurrency.is_none_or(|n| self.children.len() < n);
+        has_room && !paths_of(event).any(|path| self.paths.contains(path))
+    }
+
+    fn start(&mut self, command: &Command, event: &watcher::Event) {
+        let Some(mut child) = command.spawn(event, false) else {
+            return;
+        };
+        let paths: Vec<Box<Path>> = paths_of(event).map(Box::from).collect();
+        self.paths.extend(paths.iter().cloned());
+        let mut interrupt_rx = self.interrupt_rx.clone();
+        self.children.spawn(async move {
+            wait(&mut child, &mut interrupt_rx).await;
+            paths
+        });
+    }
+
+    //  Starts what's waiting, in order. What can't start yet holds
+    //  back whatever comes after it for the same paths.
+    fn start_waiting(
+        &mut self,
+        command: &Command,
+        waiting: &mut VecDeque<watcher::Event>,
+        limit: Limit,
+    ) {
+        let mut held = HashSet::new();
+        let mut still_waiting = VecDeque::with_capacity(waiting.len());
+        for event in waiting.drain(..) {
+            let is_held = paths_of(&event).any(|path| held.contains(path));
+            if !is_held && self.can_start(&event, limit) {
+                self.start(command, &event);
+            } else {
+                held.extend(paths_of(&event).map(Path::to_path_buf));
+                still_waiting.push_back(event);
+            }
+        }
+        *waiting = still_waiting;
+    }
+}
+
+//  Takes the place of whatever is waiting for any of the same paths,
+//  so that they get one more run between them, and the rest wait on.
+fn coalesce(waiting: &mut VecDeque<watcher::Event>, event: watcher::Event) {
+    let overlaps = |waiting: &watcher::Event| {
+        paths_of(waiting).any(|path| paths_of(&event).any(|other| other == path))
+    };
+    let at = waiting.iter().position(overlaps);
+    waiting.retain(|waiting| !overlaps(waiting));
+    match at {
+        Some(at) => waiting.insert(at, event),
+        None => waiting.push_back(event),
+    }
+}
+
+/// Runs the command for each event, within a limit.
+/// Once it's told to finish, it runs for whatever is
+/// waiting, and waits for every run to be done.
+/// Once it's interrupted, what's waiting is dropped,
+/// and what's running is asked to stop.
+pub struct Pool {
+    event_tx: UnboundedSender<watcher::Event>,
+    task: JoinHandle<()>,
+}
+
+impl Pool {
+    pub fn start(command: Command, limit: Limit, interrupt_rx: watch::Receiver<bool>) -> Pool {
+        let (event_tx, mut event_rx) = unbounded_channel::<watcher::Event>();
+        let task = task::spawn(async move {
+            let mut running = Running {
+                children: JoinSet::new(),
+                paths: HashSet::new(),
+                interrupt_rx: interrupt_rx.clone(),
+            };
+            let mut interrupt_rx = interrupt_rx;
+            let mut waiting = VecDeque::new();
+            let mut open = true;
+            let mut is_interrupted = false;
+            while open || !running.children.is_empty() {
+                tokio::select! {
+                    _ = interrupted(&mut interrupt_rx), if !is_interrupted => {
+                        is_interrupted = true;
+                        open = false;
+                        waiting.clear();
+                    }
+                    maybe_event = event_rx.recv(), if open => match maybe_event {
+                        Some(event) if waiting.is_empty() && running.can_start(&event, limit) => {
+                            running.start(&command, &event);
+                        }
+                        Some(event) => {
+                            match limit.on_busy {
+                                OnBusy::Queue => waiting.push_back(event),
+                                OnBusy::Drop => {}
+                                OnBusy::Coalesce => coalesce(&mut waiting, event),
+                            }
+                            //  It might only be waiting behind others.
+                            running.start_waiting(&command, &mut waiting, limit);
+                        }
+                        None => open = false,
+                    },
+                    Some(done) = running.children.join_next() => {
+                        for path in done.unwrap_or_default() {
+                            running.paths.remove(&path);
+                        }
+                        running.start_waiting(&command, &mut waiting, limit);
+                    }
+                }
+            }
+        });
+        Pool { event_tx, task }
+    }
+
+    pub fn run(&self, event: &watcher::Event) {
+        let _s = self.event_tx.send(event.clone());
+    }
+
+    pub async fn finish(self) {
+        drop(self.event_tx);
+        let _ = self.task.await;
+    }
+}
+
+/// How to stop a command before running it again.
+#[derive(Clone, Copy)]
+pub struct Stop {
+    pub signal: i32,
+    pub timeout: Duration,
+}
+
+// Signals by name, with or without "SIG", or by number.
+pub fn parse_signal(s: &str) -> Result<i32, String> {
+    const SIGNALS: &[(&str, i32)] = &[
+        ("HUP", libc::SIGHUP),
+        ("INT", libc::SIGINT),
+        ("QUIT", libc::SIGQUIT),
+        ("KILL", libc::SIGKILL),
+        ("USR1", libc::SIGUSR1),
+        ("USR2", libc::SIGUSR2),
+        ("TERM", libc::SIGTERM),
+    ];
+    let name = s.trim_start_matches("SIG");
+    match (SIGNALS.iter().find(|(n, _)| *n == name), s.parse::<i32>()) {
+        (Some((_, signal)), _) => Ok(*signal),
+        (None, Ok(signal)) if signal > 0 => Ok(signal),
+        _ => Err(format!("not a signal we know: {}", s)),
+    }
+}
+
+// The command runs in a process group of its own, so that
+// whatever it started is stopped along with it. Whatever
+// is left once it's done, or out of time, is killed.
+async fn stop(mut child: Child, stop: Stop) {
+    let Some(pgid) = child.id().map(|pid| pid as i32) else {
+        return;
+    };
+    unsafe { libc::kill(-pgid, stop.signal) };
+    if timeout(stop.timeout, child.wait()).await.is_err() {
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+        let _ = child.wait().await;
+    }
+    unsafe { libc::kill(-pgid, libc::SIGKILL) };
+}
+
+//  One save is often a few events: a create and a write, say.
+//  Events this close together restart the command once.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// Keeps one run of the command going, and restarts
+/// it for each event. Events which come in until they
+/// settle, or while it's being stopped, restart it once,
+/// for the last of them.
+/// Once it's interrupted, it's stopped for good.
+pub struct Restart {
+    event_tx: UnboundedSender<watcher::Event>,
+    task: JoinHandle<()>,
+}
+
+impl Restart {
+    pub fn start(
+        command: Command,
+        how: Stop,
+        first: watcher::Event,
+        mut interrupt_rx: watch::Receiver<bool>,
+    ) -> Restart {
+        let (event_tx, mut event_rx) = unbounded_channel::<watcher::Event>();
+        let task = task::spawn(async move {
+            let mut child = command.spawn(&first, true);
+            'running: loop {
+                let mut event = tokio::select! {
+                    maybe_event = event_rx.recv() => match maybe_event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                    _ = interrupted(&mut interrupt_rx) => break,
+                };
+                loop {
+                    tokio::select! {
+                        next = timeout(SETTLE, event_rx.recv()) => match next {
+                            Ok(Some(next)) => event = next,
+                            _ => break,
+                        },
+                        _ = interrupted(&mut interrupt_rx) => break 'running,
+                    }
+                }
+                if let Some(child) = child.take() {
+                    stop(child, how).await;
+                }
+                child = command.spawn(&event, true);
+            }
+            if let Some(child) = child {
+                stop(child, how).await;
+            }
+        });
+        Restart { event_tx, task }
+    }
+
+    pub fn run(&self, event: &watcher::Event) {
+        let _s = self.event_tx.send(event.clone());
+    }
+
+    /// Stops the command, and waits until it has.
+    pub async fn finish(self) {
+        drop(self.event_tx);
+        let _ = self.task.await;
+    }
+}
+
+/// How paths are written to the command's stdin, for a batch.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Separator {
+    /// Each path ends with a NUL byte, like `xargs -0` reads them
+    Nul,
+    /// Each path ends with a newline
+    Newline,
+}
+
+/// How events are gathered into one run of the command.
+#[derive(Clone, Copy)]
+pub struct Batch {
+    pub window: Duration,
+    /// Whether each event starts the window over.
+    pub until_quiet: bool,
+    pub stdin: Option<Separator>,
+}
+
+//  Linux's limit on any one argument, which the shell's command line is.
+const MAX_ARG_STRLEN: usize = 32 * 4096;
+
+//  What a run of the command can take in arguments, after our
+//  environment and what the command adds to it, with the room
+//  xargs leaves for whatever else.
+fn arg_budget(command: &Command, event: &watcher::Event) -> usize {
+    let word = size_of::<usize>();
+    let arg_max = match unsafe { libc::sysconf(libc::_SC_ARG_MAX) } {
+        n if n > 0 => n as usize,
+        _ => MAX_ARG_STRLEN,
+    };
+    let ours = std::env::vars_os().map(|(key, value)| key.len() + value.len());
+    let theirs = env_of(event)
+        .into_iter()
+        .map(|(key, value)| key.len() + value.len());
+    let added = command
+        .env
+        .iter()
+        .map(|(key, value)| key.len() + value.len());
+    let env: usize = ours
+        .chain(theirs)
+        .chain(added)
+        .map(|size| size + 2 + word)
+        .sum();
+    arg_max.saturating_sub(env).saturating_sub(2048)
+}
+
+impl Command {
+    //  Splits the paths between as few runs as the arguments fit in,
+    //  in `budget` bytes, with no argument longer than `max_arg`.
+    //  A path too long to fit even on its own gets a run of its own,
+    //  which fails, and says why.
+    fn split<'a>(
+        &self,
+        event: &watcher::Event,
+        paths: &'a [&'a Path],
+        budget: usize,
+        max_arg: usize,
+    ) -> Vec<&'a [&'a Path]> {
+        //  Each path is an argument of its own where {paths} is one,
+        //  and part of a longer one everywhere else.
+        let word = size_of::<usize>();
+        let (base, whole, within, quoted) = match &self.program {
+            Program::Shell(template) => {
+                let line = expand(template, event, &[], true).len() + 1;
+                (line, 0, template.matches("{paths}").count(), true)
+            }
+            Program::Argv(argv) => {
+                let whole = argv.iter().filter(|arg| *arg == "{paths}").count();
+                let all: usize = argv.iter().map(|arg| arg.matches("{paths}").count()).sum();
+                let base = argv
+                    .iter()
+                    .filter(|arg| *arg != "{paths}")
+                    .map(|arg| expand(arg, event, &[], false).len() + 1 + word)
+                    .sum();
+                (base, whole, all - whole, false)
+            }
+        };
+        //  Without {paths}, each run would be the same.
+        if whole + within == 0 {
+            return vec![paths];
+        }
+        let budget = match within {
+            0 => budget,
+            _ => budget.min(max_arg),
+        };
+        let per_path = |path: &Path| {
+            let bytes = path.as_os_str().as_bytes();
+            let within_size = match quoted {
+                true => quote(bytes).len(),
+                false => bytes.len(),
+            };
+            whole * (bytes.len() + 1 + word) + within * (within_size + 1)
+        };
+
+        let mut runs = Vec::new();
+        let (mut start, mut used) = (0, base);
+        for (i, path) in paths.iter().enumerate() {
+            let more = per_path(path);
+            if i > start && used + more > budget {
+                runs.push(&paths[start..i]);
+                (start, used) = (i, base);
+            }
+            used += more;
+        }
+        runs.push(&paths[start..]);
+        runs
+    }
+
+    //  One run after another, for the paths in the batch, each once,
+    //  in the order they first came. Other placeholders are for the
+    //  batch's last event.
+    async fn run_batch(
+        &self,
+        events: &[watcher::Event],
+        stdin: Option<Separator>,
+        interrupt_rx: &mut watch::Receiver<bool>,
+    ) {
+        let Some(last) = events.last() else {
+            return;
+        };
+        let mut seen = HashSet::new();
+        let paths: Vec<&Path> = events
+            .iter()
+            .flat_map(paths_of)
+            .filter(|path| seen.insert(*path))
+            .collect();
+        let runs = match stdin {
+            Some(_) => vec![&paths[..]],
+            None => self.split(last, &paths, arg_budget(self, last), MAX_ARG_STRLEN),
+        };
+        for run in runs {
+            if *interrupt_rx.borrow() {
+                return;
+            }
+            let Some(mut process) = self.to_process(last, run) else {
+                return;
+            };
+            if stdin.is_some() {
+                process.stdin(Stdio::piped());
+            }
+            let Some(mut child) = spawn(process) else {
+                continue;
+            };
+            if let (Some(mut to), Some(separator)) = (child.stdin.take(), stdin) {
+                let mut written = Vec::new();
+                for path in run {
+                    written.extend_from_slice(path.as_os_str().as_bytes());
+                    written.push(match separator {
+                        Separator::Nul => b'\0',
+                        Separator::Newline => b'\n',
+                    });
+                }
+                //  The command doesn't have to read all of it.
+                let _ = to.write_all(&written).await;
+            }
+            wait(&mut child, interrupt_rx).await;
+        }
+    }
+}
+
+/// Runs the command once for each batch of events. Batches don't
+/// overlap: what comes in during a run goes in the next batch.
+/// Once it's interrupted, the batch it was gathering is dropped,
+/// and the run going is asked to stop.
+pub struct Batcher {
+    event_tx: UnboundedSender<watcher::Event>,
+    task: JoinHandle<()>,
+}
+
+impl Batcher {
+    pub fn start(
+        command: Command,
+        batch: Batch,
+        mut interrupt_rx: watch::Receiver<bool>,
+    ) -> Batcher {
+        let (event_tx, mut event_rx) = unbounded_channel::<watcher::Event>();
+        let task = task::spawn(async move {
+            let mut open = true;
+            while open {
+                let first = tokio::select! {
+                    maybe_event = event_rx.recv() => match maybe_event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                    _ = interrupted(&mut interrupt_rx) => break,
+                };
+                let mut events = vec![first];
+                let mut deadline = Instant::now() + batch.window;
+                loop {
+                    let next = tokio::select! {
+                        next = timeout_at(deadline, event_rx.recv()) => next,
+                        _ = interrupted(&mut interrupt_rx) => return,
+                    };
+                    match next {
+                        Ok(Some(event)) => {
+                            if batch.until_quiet {
+                                deadline = Instant::now() + batch.window;
+                            }
+                            events.push(event);
+                        }
+                        Ok(None) => {
+                            open = false;
+                            break;
+                        }
+                        Err(_elapsed) => break,
+                    }
+                }
+                command
+                    .run_batch(&events, batch.stdin, &mut interrupt_rx)
+                    .await;
+            }
+        });
+        Batcher { event_tx, task }
+    }
+
+    pub fn run(&self, event: &watcher::Event) {
+        let _s = self.event_tx.send(event.clone());
+    }
+
+    /// Runs for what's left, and waits for it.
+    pub async fn finish(self) {
+        drop(self.event_tx);
+        let _ = self.task.await;
+    }
+}
+
+/// Runs a command for events, one way or another.
+pub enum Runner {
+    Each(Pool),
+    Restart(Restart),
+    Batch(Batcher),
+}
+
+impl Runner {
+    pub fn run(&self, event: &watcher::Event) {
+        match self {
+            Runner::Each(pool) => pool.run(event),
+            Runner::Restart(restart) => restart.run(event),
+            Runner::Batch(batcher) => batcher.run(event),
+        }
+    }
+
+    /// Waits for what's running to be done with,
+    /// or to have stopped, once we're interrupted.
+    pub async fn finish(self) {
+        match self {
+            Runner::Each(pool) => pool.finish().await,
+            Runner::Restart(restart) => restart.finish().await,
+            Runner::Batch(batcher) => batcher.finish().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(path: &[u8]) -> watcher::Event {
+        watcher::Event {
+            path: Path::new(OsStr::from_bytes(path)).into(),
+            associated: None,
+            root: Path::new("/w").into(),
+            what: watcher::What::Modify,
+            kind: watcher::Kind::File,
+            when: Duration::from_nanos(7),
+        }
+    }
+
+    //  What the shell makes of a command line, byte for byte.
+    fn shell_output(line: Vec<u8>) -> Vec<u8> {
+        std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(OsString::from_vec(line))
+            .output()
+            .unwrap()
+            .stdout
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(quote(b"plain"), b"'plain'");
+        assert_eq!(quote(b"it's"), b"'it'\\''s'");
+        assert_eq!(quote(b""), b"''");
+    }
+
+    #[test]
+    fn shell_gets_paths_as_they_are() {
+        let paths: &[&[u8]] = &[
+            b"/w/it's",
+            b"/w/$(touch /tmp/watcher-exec-injected)",
+            b"/w/a; echo no",
+            b"/w/`echo no`",
+            b"/w/caf\xe9 \"x\"",
+        ];
+        for path in paths {
+            let line = expand("printf %s {path}", &event(path), &[], true);
+            assert_eq!(shell_output(line), *path);
+        }
+        assert!(!Path::new("/tmp/watcher-exec-injected").exists());
+    }
+
+    #[test]
+    fn placeholders() {
+        let event = event(b"/w/f");
+        let expanded = |template| expand(template, &event, &[], false);
+        assert_eq!(expanded("{what} {kind} {when} {root}"), b"modify file 7 /w");
+        assert_eq!(expanded("{to}"), b"");
+        // Unknown names and unclosed braces are left alone.
+        assert_eq!(expanded("{nope} {path}"), b"{nope} /w/f");
+        assert_eq!(expanded("awk '{print}' {path"), b"awk '{print}' {path");
+        assert_eq!(expanded("{{path}}"), b"{/w/f}");
+    }
+
+    #[test]
+    fn argv_has_no_shell() {
+        let command = Command {
+            program: Program::Argv(vec!["echo".into(), "x={path}".into(), "{paths}".into()]),
+            cwd: None,
+            env: Vec::new(),
+        };
+        let event = event(b"/w/a b;'c'");
+        let paths = [Path::new("/w/1"), Path::new("/w/2")];
+        let process = command.to_process(&event, &paths).unwrap();
+        let args: Vec<&OsStr> = process.as_std().get_args().collect();
+        assert_eq!(args, ["x=/w/a b;'c'", "/w/1", "/w/2"]);
+    }
+
+    //  How many paths go in each run.
+    fn split_sizes(program: Program, paths: &[&Path], budget: usize, max_arg: usize) -> Vec<usize> {
+        let command = Command {
+            program,
+            cwd: None,
+            env: Vec::new(),
+        };
+        let runs = command.split(&event(b"/w/f"), paths, budget, max_arg);
+        runs.iter().map(|run| run.len()).collect()
+    }
+
+    #[test]
+    fn splits_between_runs() {
+        let word = size_of::<usize>();
+        let long = format!("/p/{}", "x".repeat(100));
+        let paths: Vec<&Path> = ["/p/aaaa", "/p/bbbb", "/p/cccc", "/p/dddd", "/p/eeee"]
+            .into_iter()
+            .map(Path::new)
+            .collect();
+
+        //  Each path is an argument: 7 bytes, a NUL, and a pointer.
+        let argv = || Program::Argv(vec!["cmd".into(), "{paths}".into()]);
+        let base = 4 + word;
+        let each = 8 + word;
+        assert_eq!(
+            split_sizes(argv(), &paths, base + 2 * each, usize::MAX),
+            [2, 2, 1]
+        );
+        assert_eq!(
+            split_sizes(argv(), &paths, base + 2 * each - 1, usize::MAX),
+            [1, 1, 1, 1, 1]
+        );
+        assert_eq!(split_sizes(argv(), &paths, 1 << 20, usize::MAX), [5]);
+
+        //  One which can't fit on its own still gets a run.
+        let with_long = [paths[0], Path::new(&long), paths[1]];
+        assert_eq!(
+            split_sizes(argv(), &with_long, base + 2 * each, usize::MAX),
+            [1, 1, 1]
+        );
+
+        //  Within a longer argument, a path is its bytes and a space,
+        //  and that argument can't be longer than max_arg.
+        let within = || Program::Argv(vec!["cmd".into(), "--files={paths}".into()]);
+        let base = 4 + word + 9 + word;
+        assert_eq!(
+            split_sizes(within(), &paths, base + 3 * 8, usize::MAX),
+            [3, 2]
+        );
+        assert_eq!(
+            split_sizes(within(), &paths, 1 << 20, base + 2 * 8),
+            [2, 2, 1]
+        );
+
+        //  The shell's command line is one argument, with quoted paths.
+        let shell = || Program::Shell("lint {paths}".into());
+        let base = "lint ".len() + 1;
+        let each = "'/p/aaaa' ".len();
+        assert_eq!(
+            split_sizes(shell(), &paths, 1 << 20, base + 2 * each),
+            [2, 2, 1]
+        );
+        assert_eq!(split_sizes(shell(), &paths, 1 << 20, MAX_ARG_STRLEN), [5]);
+
+        //  Without {paths}, there's nothing to split.
+        let shell = Program::Shell("make".into());
+        assert_eq!(split_sizes(shell, &paths, 1, 1), [5]);
+    }
+
+    #[test]
+    fn expanded_paths_are_not_expanded_again() {
+        let event = event(b"/w/{what}\xff");
+        assert_eq!(expand("{path}", &event, &[], false), b"/w/{what}\xff");
+        assert_eq!(expand("{path}", &event, &[], true), b"'/w/{what}\xff'");
+    }
+}
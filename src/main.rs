@@ -1,8 +1,12 @@
+mod exec;
+
+use clap::builder::TypedValueParser;
 use clap::Parser;
+use regex::bytes::Regex;
+use std::os::unix::ffi::OsStrExt;
 use tokio::io::stdin;
 use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
-use tokio::sync::broadcast::error::TryRecvError;
 use tokio::task;
 use tokio_stream::StreamExt;
 use watcher_cli as wtr;
@@ -11,106 +15,442 @@ use wtr::watcher;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct WatcherCliArgs {
+    #[arg(long, required = true)]
+    path: Vec<std::path::PathBuf>,
+    /// Glob, matched against absolute paths and paths below --path
     #[arg(long)]
-    path: String,
+    filter_path: Option<Vec<wtr::watcher::Glob>>,
+    /// Regex, matched against absolute paths and paths below --path
     #[arg(long)]
-    filter_path: Option<Vec<String>>,
+    filter_regex: Option<Vec<Regex>>,
     #[arg(long)]
     filter_what: Option<Vec<wtr::watcher::What>>,
     #[arg(long)]
     filter_kind: Option<Vec<wtr::watcher::Kind>>,
+    /// Glob to leave out, along with everything below what it matches.
+    /// Matching directories are never watched
+    #[arg(long)]
+    ignore: Vec<wtr::watcher::Glob>,
+    /// Leave out what .gitignore, .ignore and .git/info/exclude do
+    #[arg(long)]
+    respect_gitignore: bool,
+    /// What watches the filesystem. auto prefers fanotify,
+    /// and falls back to inotify when fanotify can't be used
+    #[arg(
+        long,
+        default_value = "auto",
+        value_parser = clap::builder::PossibleValuesParser::new(["auto", "fanotify", "inotify"])
+            .map(wtr::watcher::Backend::from)
+    )]
+    backend: wtr::watcher::Backend,
+    /// When the kernel drops events, look at the tree again
+    /// and report what changed in the meantime
+    #[arg(long)]
+    rescan: bool,
+    /// Wait for paths which don't exist yet, and
+    /// keep waiting for them when they're deleted
+    #[arg(long)]
+    wait: bool,
+    /// Say when every path is being watched, with how many directories
+    /// that took and how long. To stderr, a file descriptor (fd:N), or a file
+    #[arg(long, value_name = "TO", num_args = 0..=1, default_missing_value = "stderr")]
+    ready: Option<ReadyTo>,
+    /// Start with an "exists" event for everything already there
     #[arg(long)]
+    initial_scan: bool,
+    /// Wait until each path has been quiet for this many milliseconds,
+    /// and report what its burst of events came down to
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    debounce: u64,
+    /// Shell command to run for each event. Placeholders like {path}
+    /// are quoted for the shell
+    #[arg(long, conflicts_with = "command")]
     exec: Option<String>,
+    /// Where the command runs. Here, by default
+    #[arg(long, value_name = "DIR")]
+    exec_cwd: Option<std::path::PathBuf>,
+    /// Set in the command's environment, after the event's
+    /// WATCHER_PATH, WATCHER_WHAT, and the rest
+    #[arg(long, value_name = "KEY=VAL", value_parser = exec::parse_env)]
+    exec_env: Vec<(String, String)>,
+    /// Keep one run of the command going, from the start,
+    /// and restart it for each event
+    #[arg(long)]
+    restart: bool,
+    /// What to stop the command with before restarting it
+    #[arg(long, value_name = "SIGNAL", default_value = "TERM", value_parser = exec::parse_signal)]
+    stop_signal: i32,
+    /// How long the command has to stop before it's killed
+    #[arg(long, value_name = "MS", default_value_t = 5000)]
+    stop_timeout: u64,
+    /// How many runs of the command can go at once. No limit by default.
+    /// Runs for the same path never overlap
+    #[arg(long, value_name = "N", conflicts_with = "restart", value_parser = clap::value_parser!(u64).range(1..))]
+    exec_concurrency: Option<u64>,
+    /// What to do with an event when the command can't run for it yet
+    #[arg(long, value_enum, default_value_t = exec::OnBusy::Queue, conflicts_with = "restart")]
+    on_busy: exec::OnBusy,
+    /// Run the command once for a batch of events, instead of for
+    /// each one. {paths} is every path in the batch
+    #[arg(long, conflicts_with_all = ["restart", "exec_concurrency"])]
+    exec_batch: bool,
+    /// How long a batch gathers events for, from its first
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 100,
+        requires = "exec_batch"
+    )]
+    batch_window: u64,
+    /// Gather events until there have been none for --batch-window
+    #[arg(long, requires = "exec_batch")]
+    batch_until_quiet: bool,
+    /// Write the batch's paths to the command's stdin
+    #[arg(long, value_enum, value_name = "SEPARATOR", requires = "exec_batch")]
+    batch_stdin: Option<exec::Separator>,
+    #[arg(long, value_enum, default_value_t = Format::Legacy)]
+    format: Format,
+    /// Say which --path each event came from
+    #[arg(long)]
+    tag_root: bool,
+    /// Program to run for each event, after "--", with its arguments.
+    /// Placeholders are replaced in each argument, and no shell is involved
+    #[arg(last = true, value_name = "COMMAND")]
+    command: Vec<String>,
 }
 
-fn escape(s: String) -> String {
-    s.replace("\"", "\\\"").replace("'", "\\'")
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Format {
+    /// One JSON object per line
+    Ndjson,
+    /// A single JSON array, closed when the watcher stops
+    Json,
+    /// `"<when>":{...},` object members, one per line
+    Legacy,
 }
 
-fn have_filtered_result(
-    filter_path: &Option<Vec<String>>,
-    filter_what: &Option<Vec<wtr::watcher::What>>,
-    filter_kind: &Option<Vec<wtr::watcher::Kind>>,
-    event: &watcher::Event,
-) -> bool {
-    filter_path
+#[derive(Clone)]
+enum ReadyTo {
+    Stderr,
+    Fd(i32),
+    File(std::path::PathBuf),
+}
+
+impl<'a> From<&'a str> for ReadyTo {
+    fn from(s: &'a str) -> ReadyTo {
+        match (s, s.strip_prefix("fd:").map(str::parse)) {
+            ("stderr", _) => ReadyTo::Stderr,
+            (_, Some(Ok(fd))) => ReadyTo::Fd(fd),
+            _ => ReadyTo::File(s.into()),
+        }
+    }
+}
+
+//  Written once, as a line of its own.
+fn announce_ready(to: &ReadyTo, ready: watcher::Ready) {
+    let line = format!(
+        "s/self/ready : {} dirs in {}ms\n",
+        ready.dirs,
+        ready.elapsed.as_millis()
+    );
+    match to {
+        ReadyTo::Stderr => eprint!("{}", line),
+        ReadyTo::Fd(fd) => {
+            let _n = unsafe { libc::write(*fd, line.as_ptr() as *const libc::c_void, line.len()) };
+        }
+        ReadyTo::File(path) => {
+            if let Err(error) = std::fs::write(path, line) {
+                eprintln!("e/self/ready@{} : {}", path.to_string_lossy(), error);
+            }
+        }
+    }
+}
+
+struct Output {
+    format: Format,
+    tag_root: bool,
+    count: usize,
+    //  Roots which haven't sent their last event yet.
+    live: usize,
+    closed: bool,
+}
+
+impl Output {
+    fn open(format: Format, tag_root: bool, roots: usize) -> Output {
+        if format == Format::Json {
+            println!("[");
+        }
+        Output {
+            format,
+            tag_root,
+            count: 0,
+            live: roots,
+            closed: false,
+        }
+    }
+
+    fn write(&mut self, event: &watcher::Event) {
+        if self.closed {
+            return;
+        }
+        let json = match self.tag_root {
+            true => event.json().with_root(),
+            false => event.json(),
+        };
+        match self.format {
+            Format::Ndjson => println!("{}", json),
+            Format::Json => {
+                let sep = if self.count == 0 { " " } else { "," };
+                println!("{}{}", sep, json);
+            }
+            Format::Legacy => println!("{}", event),
+        }
+        self.count += 1;
+        if event.is_last() {
+            self.live = self.live.saturating_sub(1);
+            if self.live == 0 {
+                self.close();
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if !self.closed && self.format == Format::Json {
+            println!("]");
+        }
+        self.closed = true;
+    }
+}
+
+// What a restarted command first runs for.
+fn started(roots: &[std::path::PathBuf]) -> watcher::Event {
+    let root = roots
+        .first()
+        .map(|root| root.as_path())
+        .unwrap_or(std::path::Path::new("/"));
+    watcher::Event {
+        path: root.into(),
+        associated: None,
+        root: root.into(),
+        what: watcher::What::Create,
+        kind: watcher::Kind::Watcher,
+        when: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default(),
+    }
+}
+
+// Every filter we were given has to match.
+// Within one filter, any of its values can match.
+fn have_filtered_result(args: &WatcherCliArgs, event: &watcher::Event) -> bool {
+    let path_filters_given = args.filter_path.is_some() || args.filter_regex.is_some();
+
+    let path_ok = !path_filters_given
+        || args
+            .filter_path
+            .iter()
+            .flatten()
+            .any(|glob| event.path_matches(|p| glob.is_match(p)))
+        || args
+            .filter_regex
+            .iter()
+            .flatten()
+            .any(|regex| event.path_matches(|p| regex.is_match(p.as_os_str().as_bytes())));
+
+    let kind_ok = args
+        .filter_kind
+        .as_ref()
+        .map(|filter_kind| filter_kind.contains(&event.kind))
+        .unwrap_or(true);
+
+    let what_ok = args
+        .filter_what
         .as_ref()
-        .map(|filter_path| filter_path.contains(&event.path.to_str().unwrap().to_string()))
-        .and_then(|b| if b { Some(b) } else { None })
-        .or_else(|| {
-            filter_kind
-                .as_ref()
-                .map(|filter_kind| filter_kind.contains(&event.kind))
-                .and_then(|b| if b { Some(b) } else { None })
-        })
-        .or_else(|| {
-            filter_what
-                .as_ref()
-                .map(|filter_what| filter_what.contains(&event.what))
-                .and_then(|b| if b { Some(b) } else { None })
-        })
-        .unwrap_or(true)
+        .map(|filter_what| filter_what.contains(&event.what))
+        .unwrap_or(true);
+
+    path_ok && kind_ok && what_ok
 }
 
 async fn any_input() -> bool {
     stdin().read(&mut [0u8]).await.is_ok()
 }
 
-async fn on_watch_event(args: WatcherCliArgs, mut bc_rx: tokio::sync::broadcast::Receiver<()>) {
-    let mut watcher = watcher::watch(args.path);
+// Being interrupted or terminated stops us the same way input does,
+// so that a restarted command's process group is stopped as well,
+// except that what the command had left to do is dropped, and
+// what's running is asked to stop. A second time, we don't wait.
+async fn on_signals(
+    bc_tx: tokio::sync::broadcast::Sender<()>,
+    interrupt_tx: tokio::sync::watch::Sender<bool>,
+) {
+    use tokio::signal::unix::signal;
+    use tokio::signal::unix::SignalKind;
+    let (Ok(mut interrupt), Ok(mut terminate)) = (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) else {
+        return;
+    };
+    let mut interrupted = false;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        if interrupted {
+            std::process::exit(1);
+        }
+        interrupted = true;
+        let _sent = interrupt_tx.send(true);
+        let _sent = bc_tx.send(());
+    }
+}
 
-    bc_rx.resubscribe();
+// Returns whether we watched without errors.
+async fn on_watch_event(
+    args: WatcherCliArgs,
+    mut bc_rx: tokio::sync::broadcast::Receiver<()>,
+    interrupt_rx: tokio::sync::watch::Receiver<bool>,
+) -> bool {
+    let options = watcher::WatchOptions {
+        exclude: args.ignore.clone(),
+        backend: args.backend,
+        rescan: args.rescan,
+        wait: args.wait,
+        initial_scan: args.initial_scan,
+    };
+    let window = std::time::Duration::from_millis(args.debounce);
+    let mut watcher = watcher::watch_with(args.path.clone(), options).debounce(window);
+    for (root, backend) in watcher.roots().iter().zip(watcher.backends()) {
+        eprintln!("s/self/backend@{} : {}", root.to_string_lossy(), backend);
+    }
+    if let Some(to) = args.ready.clone() {
+        let ready = watcher.ready();
+        task::spawn(async move { announce_ready(&to, ready.await) });
+    }
+    let mut ignore = watcher::Ignore::new(args.ignore.clone());
+    if args.respect_gitignore {
+        ignore = ignore.respect_gitignore(watcher.roots());
+    }
+    let program = match (&args.exec, args.command.is_empty()) {
+        (Some(line), _) => Some(exec::Program::Shell(line.clone())),
+        (None, false) => Some(exec::Program::Argv(args.command.clone())),
+        (None, true) => None,
+    };
+    let command = program.map(|program| exec::Command {
+        program,
+        cwd: args.exec_cwd.clone(),
+        env: args.exec_env.clone(),
+    });
+    let runner = command.map(|command| match (args.restart, args.exec_batch) {
+        //  The first run is for the watcher starting.
+        (true, _) => exec::Runner::Restart(exec::Restart::start(
+            command,
+            exec::Stop {
+                signal: args.stop_signal,
+                timeout: std::time::Duration::from_millis(args.stop_timeout),
+            },
+            started(watcher.roots()),
+            interrupt_rx,
+        )),
+        (false, true) => exec::Runner::Batch(exec::Batcher::start(
+            command,
+            exec::Batch {
+                window: std::time::Duration::from_millis(args.batch_window),
+                until_quiet: args.batch_until_quiet,
+                stdin: args.batch_stdin,
+            },
+            interrupt_rx,
+        )),
+        (false, false) => exec::Runner::Each(exec::Pool::start(
+            command,
+            exec::Limit {
+                concurrency: args.exec_concurrency.map(|n| n as usize),
+                on_busy: args.on_busy,
+            },
+            interrupt_rx,
+        )),
+    });
+    //  Events go to the command when there is one, and to stdout otherwise.
+    let mut output = match runner {
+        Some(_) => None,
+        None => Some(Output::open(
+            args.format,
+            args.tag_root,
+            watcher.roots().len(),
+        )),
+    };
+    let mut ok = true;
+    let mut stopping = false;
 
     loop {
-        match bc_rx.try_recv() {
-            Ok(_) => {
+        let event = tokio::select! {
+            //  The adapters' last events are still on their way.
+            _ = bc_rx.recv(), if !stopping => {
                 watcher.stop();
+                stopping = true;
+                continue;
             }
-            Err(TryRecvError::Closed) => {
-                watcher.stop();
-            }
-            _ => {}
-        }
-        if let Some(event) = watcher.next().await {
-            // println!("alaalskdjfnalslalala{}", event);
-            if have_filtered_result(
-                &args.filter_path,
-                &args.filter_what,
-                &args.filter_kind,
-                &event,
-            ) {
-                if let Some(exec) = &args.exec {
-                    let mut s = exec
-                        .to_string()
-                        .replace("{event}", &escape(event.to_string()))
-                        .replace("{path}", &escape(event.path.to_string_lossy().into_owned()))
-                        .replace("{what}", &event.what.to_string())
-                        .replace("{kind}", &event.kind.to_string())
-                        .replace("{when}", &event.when.as_nanos().to_string());
-                    s.push('\0');
-                    task::spawn_blocking(move || unsafe { libc::system(s.as_ptr() as *const i8) });
-                } else {
-                    println!("{}", event);
+            maybe_event = watcher.next() => match maybe_event {
+                Some(Ok(event)) => event,
+                Some(Err(error)) => {
+                    eprintln!("{}", error);
+                    ok = false;
+                    continue;
                 }
+                None => break,
+            },
+        };
+
+        ignore.update(&event);
+
+        if !ignore.is_event_ignored(&event) && have_filtered_result(&args, &event) {
+            if let Some(runner) = &runner {
+                // The watcher's own events only run commands when asked for.
+                if event.kind != watcher::Kind::Watcher || args.filter_kind.is_some() {
+                    runner.run(&event);
+                }
+            } else if let Some(output) = &mut output {
+                output.write(&event);
             }
         }
     }
+
+    if let Some(output) = &mut output {
+        output.close();
+    }
+    if let Some(runner) = runner {
+        runner.finish().await;
+    }
+
+    ok
 }
 
 fn main() {
-    Runtime::new().unwrap().block_on(async {
-        let args = WatcherCliArgs::parse();
+    let args = WatcherCliArgs::parse();
+
+    let runtime = Runtime::new().unwrap();
 
+    let ok = runtime.block_on(async {
         let (bc_tx, bc_rx) = tokio::sync::broadcast::channel(1);
+        let (interrupt_tx, interrupt_rx) = tokio::sync::watch::channel(false);
 
-        let watching = task::spawn(on_watch_event(args, bc_rx));
+        let watching = task::spawn(on_watch_event(args, bc_rx, interrupt_rx));
 
-        task::spawn(any_input())
-            .await
-            .map(|_| {
-                let _sent = bc_tx.send(());
-                watching.abort();
-            })
-            .unwrap();
+        task::spawn(on_signals(bc_tx.clone(), interrupt_tx));
+        task::spawn(async move {
+            any_input().await;
+            let _sent = bc_tx.send(());
+        });
+
+        watching.await.unwrap_or(false)
     });
+
+    // The stdin reader may still be blocked on a read.
+    runtime.shutdown_background();
+
+    if !ok {
+        std::process::exit(1);
+    }
 }
@@ -1,6 +1,13 @@
 pub mod adapter;
+pub mod debounce;
+pub mod error;
 pub mod event;
+mod file;
+pub mod filter;
+pub mod ignore;
 pub mod platform;
+pub mod snapshot;
+mod wait;
 pub mod watch;
 
-pub use {event::*, watch::*};
+pub use {debounce::*, error::*, event::*, filter::*, ignore::*, snapshot::*, watch::*};
@@ -160,8 +160,8 @@ pub mod sys {
 
 use crate::watcher::*;
 use std::collections::HashSet;
-use std::path::PathBuf;
-use std::sync::mpsc::{Receiver as SyncReceiver, Sender as SyncSender, TryRecvError::Empty};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver as SyncReceiver, TryRecvError::Empty};
 use std::thread::sleep;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use sys::os::darwin::cf::*;
@@ -174,7 +174,8 @@ use sys::*;
 
 #[repr(C)]
 struct ArgPtr<'a> {
-    event_tx: &'a SyncSender<Event>,
+    event_tx: &'a EventSender,
+    root: &'a Path,
     // callback: &'a Callback,
     seen_created_paths: &'a mut HashSet<String>,
 }
@@ -333,7 +334,49 @@ const fn what_from(flag: u32) -> What {
     }
 }
 
-fn now() -> Duration {
+//  FSEvents doesn't walk the tree for us, so the initial scan does.
+//  Symlinks aren't followed, and excluded directories are sent,
+//  but not looked into, like the marking walk on Linux does.
+fn send_existing(event_tx: &EventSender, root: &Path, options: &WatchOptions) {
+    let mut dirvec = vec![root.to_path_buf()];
+    while let Some(dir) = dirvec.pop() {
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            for dirent in entries.flatten() {
+                let kind = match dirent.file_type() {
+                    Ok(t) if t.is_dir() => Kind::Dir,
+                    Ok(t) if t.is_symlink() => Kind::SymLink,
+                    Ok(t) if t.is_file() => Kind::File,
+                    _ => Kind::Other,
+                };
+                if kind == Kind::Dir && !options.excludes(&dirent.path(), root) {
+                    dirvec.push(dirent.path());
+                }
+                let _s = event_tx.send(Ok(Event {
+                    path: dirent.path().into(),
+                    associated: None,
+                    root: root.into(),
+                    what: What::Exists,
+                    kind,
+                    when: now(),
+                }));
+            }
+        }
+    }
+}
+
+//  Something about the watcher itself: that it started, or stopped.
+fn watcher_event(root: &Path, what: What) -> Event {
+    Event {
+        path: root.into(),
+        associated: None,
+        root: root.into(),
+        what,
+        kind: Kind::Watcher,
+        when: now(),
+    }
+}
+
+pub(crate) fn now() -> Duration {
     match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(since_epoch) => since_epoch,
         Err(_) => Duration::from_nanos(0),
@@ -357,7 +400,11 @@ extern "C" fn event_recv(
     if !any_null!(context, recv_paths, recv_flags) {
         let arg_ptr = unsafe { &mut *(context as *mut ArgPtr) };
 
-        let (event_tx, scatmap) = (&mut arg_ptr.event_tx, &mut arg_ptr.seen_created_paths);
+        let (event_tx, root, scatmap) = (
+            &mut arg_ptr.event_tx,
+            arg_ptr.root,
+            &mut arg_ptr.seen_created_paths,
+        );
 
         for i in 0..recv_count as isize {
             if let Some(path_str) = path_from_event_at(recv_paths, i) {
@@ -369,12 +416,14 @@ extern "C" fn event_recv(
                     ($flag:expr) => {
                         if inflags($flag) {
                             event_tx
-                                .send(Event {
+                                .send(Ok(Event {
                                     path: pathbuf.clone().into(),
+                                    associated: None,
+                                    root: root.into(),
                                     what: what_from($flag),
                                     kind: kind_from(flags),
                                     when: now(),
-                                })
+                                }))
                                 .unwrap_or_default();
                         }
                     };
@@ -398,7 +447,20 @@ extern "C" fn event_recv(
 }
 
 // pub fn watch(path: String, callback: Box<Callback>, rx: Receiver<bool>) -> bool {
-pub fn open(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<bool>) -> bool {
+//  There's only FSEvents here.
+pub fn resolve(_path: &Path, backend: Backend) -> Backend {
+    backend
+}
+
+//  FSEvents watches the whole tree from the root down,
+//  so there's nothing to exclude when we register it.
+pub fn open(
+    path: PathBuf,
+    options: &WatchOptions,
+    event_tx: EventSender,
+    ready_tx: ReadySender,
+    ctl_rx: SyncReceiver<bool>,
+) -> bool {
     const DELAY: Duration = Duration::from_millis(16);
 
     let is_living = || match ctl_rx.try_recv() {
@@ -410,15 +472,26 @@ pub fn open(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<bool
 
     let mut seen_created_paths = HashSet::<String>::new();
 
+    //  macOS file names are UTF-8 already.
+    let root = path;
+    let path = root.to_string_lossy().into_owned();
+
     let mut arg_ptr = ArgPtr {
         event_tx: &event_tx,
+        root: &root,
         seen_created_paths: &mut seen_created_paths,
     };
 
     let stream_resources = event_stream_open(path, event_recv, &mut arg_ptr);
 
-    match stream_resources {
+    let ok = match stream_resources {
         Some(stream_resources) => {
+            let _s = event_tx.send(Ok(watcher_event(&root, What::Create)));
+            //  FSEvents doesn't tell us what's below the root.
+            let _s = ready_tx.send((1, std::time::Instant::now()));
+            if options.initial_scan {
+                send_existing(&event_tx, &root, options);
+            }
             loop {
                 if is_living() {
                     sleep(DELAY);
@@ -430,5 +503,8 @@ pub fn open(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<bool
         }
 
         None => false,
-    }
+    };
+    //  However we got here, nothing more is coming from this root.
+    let _s = event_tx.send(Ok(watcher_event(&root, What::Destroy)));
+    ok
 }
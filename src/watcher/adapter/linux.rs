@@ -2,18 +2,117 @@ mod fanotify;
 mod inotify;
 
 use crate::watcher::*;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::mpsc::Receiver as SyncReceiver;
-use std::sync::mpsc::Sender as SyncSender;
 
-pub fn open(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<bool>) -> bool {
-    let is_user_root = unsafe { libc::geteuid() } == 0;
+//  Sends an event on, after telling the snapshot about it.
+fn send(event_tx: &EventSender, snapshot: &mut Option<Snapshot>, event: Event) {
+    if let Some(snapshot) = snapshot {
+        snapshot.update(&event);
+    }
+    let _s = event_tx.send(Ok(event));
+}
 
-    if is_user_root {
-        // println!("fanotify");
-        fanotify::watch(path, event_tx, ctl_rx)
-    } else {
-        inotify::watch(path, event_tx, ctl_rx)
+//  Tells the user something about the watcher itself:
+//  that it started, stopped, or lost events or a mount.
+fn send_watcher(
+    event_tx: &EventSender,
+    path: &Path,
+    root: &Path,
+    what: What,
+    when: std::time::Duration,
+) {
+    let _s = event_tx.send(Ok(Event {
+        path: path.into(),
+        associated: None,
+        root: root.into(),
+        what,
+        kind: Kind::Watcher,
+        when,
+    }));
+}
+
+//  Tells the user that the kernel dropped events for this root
+//  and, if we keep a snapshot of it, what changed while it did.
+//  Returns what the rescan found, so the caller can watch new
+//  directories.
+fn send_overflow(
+    event_tx: &EventSender,
+    root: &Path,
+    snapshot: &mut Option<Snapshot>,
+    when: std::time::Duration,
+) -> Vec<Event> {
+    send_watcher(event_tx, root, root, What::Overflow, when);
+    let found = match snapshot {
+        Some(snapshot) => snapshot.rescan(),
+        None => Vec::new(),
+    };
+    for event in &found {
+        let _s = event_tx.send(Ok(event.clone()));
     }
+    found
+}
+
+pub(crate) fn now() -> std::time::Duration {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch,
+        Err(_) => std::time::Duration::from_nanos(0),
+    }
+}
+
+//  An entry the marking walk came across, for the initial scan.
+fn existing(path: PathBuf, root: &Path, file_type: Option<std::fs::FileType>) -> Event {
+    let kind = match file_type {
+        Some(t) if t.is_dir() => Kind::Dir,
+        Some(t) if t.is_symlink() => Kind::SymLink,
+        Some(t) if t.is_file() => Kind::File,
+        _ => Kind::Other,
+    };
+    Event {
+        path: path.into(),
+        associated: None,
+        root: root.into(),
+        what: What::Exists,
+        kind,
+        when: now(),
+    }
+}
+
+//  Which file a path names, by its device and inode,
+//  so that we can tell when the root is gone or replaced.
+fn identity(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path)
+        .ok()
+        .map(|meta| (meta.dev(), meta.ino()))
+}
+
+/// Which backend will watch this path.
+/// fanotify, when we can use it, and inotify otherwise.
+pub fn resolve(path: &Path, backend: Backend) -> Backend {
+    match backend {
+        Backend::Auto if fanotify::can_watch(path) => Backend::Fanotify,
+        Backend::Auto => Backend::Inotify,
+        backend => backend,
+    }
+}
+
+pub fn open(
+    path: PathBuf,
+    options: &WatchOptions,
+    event_tx: EventSender,
+    ready_tx: ReadySender,
+    ctl_rx: SyncReceiver<bool>,
+) -> bool {
+    let root = path.clone();
+    let ok = match resolve(&path, options.backend) {
+        Backend::Fanotify => fanotify::watch(path, options, event_tx.clone(), ready_tx, ctl_rx),
+        _ => inotify::watch(path, options, event_tx.clone(), ready_tx, ctl_rx),
+    };
+    //  However we got here, nothing more is coming from this root.
+    send_watcher(&event_tx, &root, &root, What::Destroy, now());
+    ok
 
     // macro_rules! cond{
     //     ($($pred:expr => $body:expr,),+ $default:expr) => {
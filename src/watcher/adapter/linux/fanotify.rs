@@ -1,14 +1,16 @@
+use super::{existing, identity, now, send, send_overflow, send_watcher};
 use crate::watcher::*;
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::ffi::OsStr;
 use std::fs;
 use std::ops::Index;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc::Receiver as SyncReceiver;
-use std::sync::mpsc::Sender as SyncSender;
 
 #[allow(dead_code)]
 pub mod sys {
@@ -43,16 +45,21 @@ pub mod sys {
 
             pub const FAN_MARK_ADD: u32 = 0x00000001;
             pub const FAN_ONDIR: u64 = 0x40000000;
+            pub const FAN_EVENT_ON_CHILD: u64 = 0x08000000;
             pub const FAN_CREATE: u64 = 0x00000100;
             pub const FAN_DELETE: u64 = 0x00000200;
             pub const FAN_MODIFY: u64 = 0x00000002;
+            pub const FAN_ATTRIB: u64 = 0x00000004;
             pub const FAN_MOVED_TO: u64 = 0x00000080;
             pub const FAN_MOVED_FROM: u64 = 0x00000040;
             pub const FAN_MOVE: u64 = FAN_MOVED_FROM | FAN_MOVED_TO;
+            pub const FAN_RENAME: u64 = 0x10000000;
             pub const FAN_DELETE_SELF: u64 = 0x00000400;
             pub const FAN_MOVE_SELF: u64 = 0x00000800;
             pub const FAN_EVENT_METADATA_LEN: usize = size_of::<fanotify_event_metadata>();
             pub const FAN_EVENT_INFO_TYPE_DFID_NAME: usize = 2;
+            pub const FAN_EVENT_INFO_TYPE_OLD_DFID_NAME: usize = 10;
+            pub const FAN_EVENT_INFO_TYPE_NEW_DFID_NAME: usize = 12;
 
             //  struct file_handle {
             //      // Size of f_handle [in, out]
@@ -118,7 +125,36 @@ const EVENT_BUF_LEN: usize = 4096;
 const DELAY_MS: i32 = 16;
 const EVENT_WAIT_QUEUE_MAX: i32 = 64;
 
-type MarkSet = std::collections::HashSet<i32>;
+const MARK_MASK: u64 = {
+    use sys::os::linux::*;
+    FAN_ONDIR
+        | FAN_EVENT_ON_CHILD
+        | FAN_CREATE
+        | FAN_MODIFY
+        | FAN_ATTRIB
+        | FAN_DELETE
+        | FAN_MOVE
+        | FAN_DELETE_SELF
+        | FAN_MOVE_SELF
+};
+
+//  Renames as one event, with both names, instead of two halves.
+const RENAME_MARK_MASK: u64 = {
+    use sys::os::linux::*;
+    (MARK_MASK & !FAN_MOVE) | FAN_RENAME
+};
+
+const FAN_INIT_FLAGS: u32 = {
+    use sys::os::linux::*;
+    FAN_CLASS_NOTIF | FAN_REPORT_DFID_NAME | FAN_UNLIMITED_QUEUE | FAN_UNLIMITED_MARKS
+};
+const FAN_INIT_OPT_FLAGS: u32 = {
+    use sys::os::linux::*;
+    (O_RDONLY | O_NONBLOCK | O_CLOEXEC) as u32
+};
+
+//  The directories we've marked, by device and inode.
+type MarkSet = std::collections::HashSet<(u64, u64)>;
 
 struct SystemResources {
     valid: bool,
@@ -127,31 +163,55 @@ struct SystemResources {
     mark_set: MarkSet,
 }
 
-fn now() -> std::time::Duration {
-    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-        Ok(since_epoch) => since_epoch,
-        Err(_) => std::time::Duration::from_nanos(0),
-    }
-}
-
 fn errno() -> i32 {
     unsafe { *libc::__errno_location() }
 }
 
-fn strerrno() -> String {
-    unsafe { core::ffi::CStr::from_ptr(libc::strerror(errno())) }
-        .to_string_lossy()
-        .into_owned()
+//  FAN_RENAME is from Linux 5.17. Older kernels refuse it as an
+//  unknown flag before they look at the path, so any path will do.
+//  Without it, fanotify can't tell which halves of a rename go
+//  together, and we report them apart.
+fn mark_mask() -> u64 {
+    use sys::os::linux::*;
+
+    static MASK: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    *MASK.get_or_init(|| {
+        let watch_fd = unsafe { fanotify_init(FAN_INIT_FLAGS, FAN_INIT_OPT_FLAGS) };
+        if watch_fd < 0 {
+            return MARK_MASK;
+        }
+        let marked = unsafe {
+            fanotify_mark(
+                watch_fd,
+                FAN_MARK_ADD,
+                RENAME_MARK_MASK,
+                AT_FDCWD,
+                c"/".as_ptr(),
+            )
+        };
+        let refused = marked < 0 && errno() == libc::EINVAL;
+        unsafe { libc::close(watch_fd) };
+        match refused {
+            true => MARK_MASK,
+            false => RENAME_MARK_MASK,
+        }
+    })
 }
 
-fn markwalk_recursive(mut mark_set: &mut MarkSet, watch_fd: i32, topdir: &Path) {
+fn markwalk_recursive(
+    mark_set: &mut MarkSet,
+    watch_fd: i32,
+    topdir: &Path,
+    options: &WatchOptions,
+    mut found: Option<&mut Vec<Event>>,
+) {
     use std::os::unix::fs::MetadataExt;
 
     const DIR_Q_RSRV_COUNT: usize = 4096 * 8;
 
     // let start_time = std::time::SystemTime::now();
 
-    mark_sys(&topdir, watch_fd, &mut mark_set);
+    mark_sys(topdir, watch_fd, mark_set);
 
     let mut inode_set = HashSet::<u64>::new();
 
@@ -159,18 +219,47 @@ fn markwalk_recursive(mut mark_set: &mut MarkSet, watch_fd: i32, topdir: &Path)
 
     dir_queue.reserve(DIR_Q_RSRV_COUNT);
 
+    //  Directories we got to through a symlink. They're marked and
+    //  walked once the rest are, so that what's below the root is
+    //  reported by its own path, and what's only below a symlink
+    //  isn't reported at all.
+    let mut linked_queue = VecDeque::<PathBuf>::new();
+
     // println!("cap of dir queue: {}", dir_queue.capacity());
 
     'ol: loop {
-        if let Some(nexttop) = dir_queue.pop_front() {
+        let next = match dir_queue.pop_front() {
+            Some(nexttop) => Some((nexttop, false)),
+            None => linked_queue.pop_front().map(|nexttop| (nexttop, true)),
+        };
+        if let Some((nexttop, linked)) = next {
+            if linked {
+                let Ok(meta) = fs::metadata(&nexttop) else {
+                    continue 'ol;
+                };
+                if !inode_set.insert(meta.ino()) || !mark_sys(&nexttop, watch_fd, mark_set) {
+                    continue 'ol;
+                }
+            }
             if let Ok(mut entries) = fs::read_dir(nexttop) {
                 for maybe_dirent in entries.by_ref() {
                     if let Ok(dirent) = maybe_dirent {
+                        let file_type = dirent.file_type().ok();
+                        let is_link = linked || file_type.is_some_and(|t| t.is_symlink());
+                        if let Some(found) = found.as_mut().filter(|_| !linked) {
+                            found.push(existing(dirent.path(), topdir, file_type));
+                        }
                         if let Ok(meta) = fs::metadata(dirent.path()) {
                             let ino = meta.ino();
-                            if !inode_set.contains(&ino) {
+                            if options.excludes(&dirent.path(), topdir) {
+                                continue;
+                            } else if is_link {
+                                if meta.is_dir() {
+                                    linked_queue.push_back(dirent.path());
+                                }
+                            } else if !inode_set.contains(&ino) {
                                 inode_set.insert(ino);
-                                if mark_sys(&dirent.path(), watch_fd, &mut mark_set) {
+                                if mark_sys(&dirent.path(), watch_fd, mark_set) {
                                     dir_queue.push_back(dirent.path());
                                 }
                             }
@@ -195,27 +284,33 @@ fn markwalk_recursive(mut mark_set: &mut MarkSet, watch_fd: i32, topdir: &Path)
     // println!("cap of dir queue: {}", dir_queue.capacity());
 }
 
-fn make_mark_set(watch_fd: RawFd, base_path: &Path) -> MarkSet {
+fn make_mark_set(
+    watch_fd: RawFd,
+    base_path: &Path,
+    options: &WatchOptions,
+    found: Option<&mut Vec<Event>>,
+) -> MarkSet {
     const MARK_SET_RSRV_COUNT: usize = 256;
 
     let mut mark_set = MarkSet::new();
 
     mark_set.reserve(MARK_SET_RSRV_COUNT);
 
-    markwalk_recursive(&mut mark_set, watch_fd, base_path);
+    markwalk_recursive(&mut mark_set, watch_fd, base_path, options, found);
 
     mark_set
 }
 
-fn make_system_resources(base_path: &Path) -> SystemResources {
+fn make_system_resources(
+    base_path: &Path,
+    options: &WatchOptions,
+    event_tx: &EventSender,
+    found: Option<&mut Vec<Event>>,
+) -> SystemResources {
     use sys::os::linux::*;
 
-    const FAN_INIT_FLAGS: u32 =
-        FAN_CLASS_NOTIF | FAN_REPORT_DFID_NAME | FAN_UNLIMITED_QUEUE | FAN_UNLIMITED_MARKS;
-    const FAN_INIT_OPT_FLAGS: u32 = (O_RDONLY | O_NONBLOCK | O_CLOEXEC) as u32;
-
-    let do_error = |msg: &str, watch_fd: i32, event_fd: i32| -> SystemResources {
-        println!("{} : {}", msg, strerrno());
+    let do_error = |error: fn(i32) -> WatchError, watch_fd: i32, event_fd: i32| {
+        let _s = event_tx.send(Err(error(errno())));
         SystemResources {
             valid: false,
             watch_fd,
@@ -246,16 +341,16 @@ fn make_system_resources(base_path: &Path) -> SystemResources {
                     valid: true,
                     watch_fd,
                     event_fd,
-                    mark_set: make_mark_set(watch_fd, base_path),
+                    mark_set: make_mark_set(watch_fd, base_path, options, found),
                 }
             } else {
-                do_error("e/sys/epoll_ctl", watch_fd, event_fd)
+                do_error(WatchError::EpollCtl, watch_fd, event_fd)
             }
         } else {
-            do_error("e/sys/epoll_create", watch_fd, event_fd)
+            do_error(WatchError::EpollCreate, watch_fd, event_fd)
         }
     } else {
-        do_error("e/sys/fanotify_init", watch_fd, -1)
+        do_error(WatchError::FanotifyInit, watch_fd, -1)
     }
 }
 
@@ -274,7 +369,7 @@ fn close_system_resources(sr: &mut SystemResources) -> bool {
 //     println!(" pid: {}", unsafe { *mtd }.pid);
 // }
 
-fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event> {
+fn promote(mtd: *const sys::os::linux::fanotify_event_metadata, root: &Path) -> Option<Event> {
     // (bool, PathBuf, What, Kind) {
     use libc::close;
     use libc::readlink;
@@ -288,8 +383,8 @@ fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event>
                       dir_fid_info: *const fanotify_event_info_fid,
                       dir_fh: *const file_handle,
                       dir_name_len: usize| unsafe {
-        const CWD_CSTR: *const i8 = b".\0".as_ptr() as *const i8;
-        const FMT_CSTR: *const i8 = b"/%s\0".as_ptr() as *const i8;
+        const CWD_CSTR: *const i8 = c".".as_ptr();
+        const FMT_CSTR: *const i8 = c"/%s".as_ptr();
 
         let name_info: *const i8 = dir_fid_info.add(1) as *const i8;
         let file_name: *const i8 = name_info
@@ -318,17 +413,35 @@ fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event>
 
     let dir_fh: *mut file_handle = unsafe { dir_fid_info.add(1) as *mut file_handle };
 
-    let what = unsafe {
-        match (*mtd).mask & FAN_CREATE != 0 {
-            true => What::Create,
-            false => match (*mtd).mask & FAN_DELETE != 0 {
-                true => What::Destroy,
-                false => What::Other,
+    let mask = unsafe { mtd.read_unaligned() }.mask;
+
+    //  The parent directory's mark already reports these
+    //  as a delete or a move of one of its entries.
+    if mask & (FAN_DELETE_SELF | FAN_MOVE_SELF) != 0 {
+        return None;
+    }
+
+    //  Events for the same name can be merged by the kernel,
+    //  so a single mask might hold several of these. We report
+    //  the one which says the most about the file.
+    let what = match mask & FAN_CREATE != 0 {
+        true => What::Create,
+        false => match mask & FAN_DELETE != 0 {
+            true => What::Destroy,
+            false => match mask & FAN_MOVE != 0 {
+                true => What::Rename,
+                false => match mask & FAN_MODIFY != 0 {
+                    true => What::Modify,
+                    false => match mask & FAN_ATTRIB != 0 {
+                        true => What::Owner,
+                        false => What::Other,
+                    },
+                },
             },
-        }
+        },
     };
 
-    let kind = match unsafe { *mtd }.mask & FAN_ONDIR != 0 {
+    let kind = match mask & FAN_ONDIR != 0 {
         true => Kind::Dir,
         false => Kind::File,
     };
@@ -346,7 +459,7 @@ fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event>
 
     if fd > 0 {
         const FS_PROC_PATH_BUF_LEN: usize = 128;
-        const FS_PROC_PATH_FMT_CSTR: *const i8 = b"/proc/self/fd/%d\0".as_ptr() as *const i8;
+        const FS_PROC_PATH_FMT_CSTR: *const i8 = c"/proc/self/fd/%d".as_ptr();
 
         let mut fs_proc_path_buf: [u8; FS_PROC_PATH_BUF_LEN] = [0; FS_PROC_PATH_BUF_LEN];
 
@@ -382,13 +495,18 @@ fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event>
             // path_buf[dirname_len] = '\0';
             path_imbue(&mut path_buf, dir_fid_info, dir_fh, dirname_len);
 
-            let path_str = unsafe { std::str::from_utf8_unchecked(&path_buf) };
-            let path = Path::new(&path_str);
+            let path_len = path_buf
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(EVENT_BUF_LEN);
+            let path = Path::new(OsStr::from_bytes(&path_buf[..path_len]));
 
             // println!("have path: {}", ret.to_str().unwrap());
 
             Some(Event {
                 path: path.into(),
+                associated: None,
+                root: root.into(),
                 what,
                 kind,
                 when: now(),
@@ -401,11 +519,16 @@ fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event>
     } else {
         path_imbue(&mut path_buf, dir_fid_info, dir_fh, 0);
 
-        let path_str = unsafe { std::str::from_utf8_unchecked(&path_buf) };
-        let path = Path::new(&path_str);
+        let path_len = path_buf
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(EVENT_BUF_LEN);
+        let path = Path::new(OsStr::from_bytes(&path_buf[..path_len]));
 
         Some(Event {
             path: path.into(),
+            associated: None,
+            root: root.into(),
             what,
             kind,
             when: now(),
@@ -415,84 +538,242 @@ fn promote(mtd: *const sys::os::linux::fanotify_event_metadata) -> Option<Event>
     }
 }
 
-fn mark_sys(full_path: &Path, watch_fd: i32, mark_set: &mut MarkSet) -> bool {
+//  The directory a file handle names, with the name in it.
+fn dfid_name_path(info: *const sys::os::linux::fanotify_event_info_fid) -> Option<PathBuf> {
+    use std::mem::size_of;
     use sys::os::linux::*;
-    if full_path.is_dir() {
-        const FLAGS: u32 = FAN_MARK_ADD;
-        const MASK: u64 = FAN_ONDIR
-            | FAN_CREATE
-            | FAN_MODIFY
-            | FAN_DELETE
-            | FAN_MOVE
-            | FAN_DELETE_SELF
-            | FAN_MOVE_SELF;
-
-        let full_path_cstr = full_path.as_os_str().as_ref() as *const std::ffi::OsStr as *const i8;
-
-        let wd = unsafe { fanotify_mark(watch_fd, FLAGS, MASK, AT_FDCWD, full_path_cstr) };
-        if wd >= 0 {
-            mark_set.insert(wd);
-            true
-        } else {
-            // println!(
-            //     "oops, while marking, bad fanotify mark call :: path: {} :: errno: {}",
-            //     unsafe { core::ffi::CStr::from_ptr(full_path_cstr as *const i8).to_string_lossy() },
-            //     strerrno()
-            // );
-            false
+
+    let handle = unsafe { (info as *const u8).add(size_of::<fanotify_event_info_fid>()) };
+    let handle_bytes = unsafe { (handle as *const u32).read_unaligned() } as usize;
+    let name = unsafe {
+        std::ffi::CStr::from_ptr(handle.add(size_of::<file_handle>() + handle_bytes) as *const _)
+    };
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            AT_FDCWD,
+            handle,
+            O_RDONLY | O_CLOEXEC | O_PATH | O_NONBLOCK,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+    let dir = fs::read_link(format!("/proc/self/fd/{}", fd));
+    unsafe { libc::close(fd as i32) };
+
+    match name.to_bytes() {
+        b"" | b"." => dir.ok(),
+        name => Some(dir.ok()?.join(OsStr::from_bytes(name))),
+    }
+}
+
+//  Both names of a rename, from its one event. A name in a directory
+//  we haven't marked isn't there, so a move into or out of the tree
+//  has only the name on our side of it.
+fn renamed(mtd: *const sys::os::linux::fanotify_event_metadata, root: &Path) -> Option<Event> {
+    use std::mem::size_of;
+    use sys::os::linux::*;
+
+    let meta = unsafe { mtd.read_unaligned() };
+    let (mut from, mut to) = (None, None);
+    let mut at = meta.metadata_len as usize;
+    while at + size_of::<fanotify_event_info_fid>() <= meta.event_len as usize {
+        let info = unsafe { (mtd as *const u8).add(at) } as *const fanotify_event_info_fid;
+        let header = unsafe { (info as *const fanotify_event_info_header).read_unaligned() };
+        if header.len == 0 {
+            break;
+        }
+        match header.info_type as usize {
+            FAN_EVENT_INFO_TYPE_OLD_DFID_NAME => from = dfid_name_path(info),
+            FAN_EVENT_INFO_TYPE_NEW_DFID_NAME => to = dfid_name_path(info),
+            _ => {}
         }
+        at += header.len as usize;
+    }
+
+    let (path, associated) = match (from, to) {
+        (Some(from), to) => (from, to),
+        (None, Some(to)) => (to, None),
+        (None, None) => return None,
+    };
+    Some(Event {
+        path: path.into(),
+        associated: associated.map(PathBuf::into_boxed_path),
+        root: root.into(),
+        what: What::Rename,
+        kind: match meta.mask & FAN_ONDIR != 0 {
+            true => Kind::Dir,
+            false => Kind::File,
+        },
+        when: now(),
+    })
+}
+
+fn path_cstring(path: &Path) -> Option<std::ffi::CString> {
+    std::ffi::CString::new(path.as_os_str().as_bytes()).ok()
+}
+
+fn mark_sys(full_path: &Path, watch_fd: i32, mark_set: &mut MarkSet) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    use sys::os::linux::*;
+
+    const FLAGS: u32 = FAN_MARK_ADD;
+
+    let meta = match fs::metadata(full_path) {
+        Ok(meta) if meta.is_dir() => meta,
+        _ => return false,
+    };
+    let Some(full_path_cstring) = path_cstring(full_path) else {
+        return false;
+    };
+    let full_path_cstr = full_path_cstring.as_ptr();
+
+    let wd = unsafe { fanotify_mark(watch_fd, FLAGS, mark_mask(), AT_FDCWD, full_path_cstr) };
+    if wd >= 0 {
+        mark_set.insert((meta.dev(), meta.ino()));
+        true
     } else {
+        // println!(
+        //     "oops, while marking, bad fanotify mark call :: path: {} :: errno: {}",
+        //     unsafe { core::ffi::CStr::from_ptr(full_path_cstr).to_string_lossy() },
+        //     strerrno()
+        // );
         false
     }
 }
 
+/// Whether fanotify can watch this path. We need the capabilities
+/// for it, a kernel which reports file names (5.9 or later), and a
+/// filesystem which can be marked.
+pub fn can_watch(path: &Path) -> bool {
+    use sys::os::linux::*;
+
+    let Some(path_cstring) = path_cstring(path) else {
+        return false;
+    };
+
+    let watch_fd = unsafe { fanotify_init(FAN_INIT_FLAGS, FAN_INIT_OPT_FLAGS) };
+    if watch_fd < 0 {
+        return false;
+    }
+
+    let marked = unsafe {
+        fanotify_mark(
+            watch_fd,
+            FAN_MARK_ADD,
+            MARK_MASK,
+            AT_FDCWD,
+            path_cstring.as_ptr(),
+        )
+    } >= 0;
+
+    unsafe { libc::close(watch_fd) };
+
+    marked
+}
+
 fn unmark_sys(full_path: &Path, watch_fd: i32, mark_set: &mut MarkSet) -> bool {
+    use std::os::unix::fs::MetadataExt;
     use sys::os::linux::*;
 
-    if full_path.is_dir() {
-        const FLAGS: u32 = FAN_MARK_REMOVE;
-        const MASK: u64 = FAN_ONDIR
-            | FAN_CREATE
-            | FAN_MODIFY
-            | FAN_DELETE
-            | FAN_MOVE
-            | FAN_DELETE_SELF
-            | FAN_MOVE_SELF;
+    const FLAGS: u32 = FAN_MARK_REMOVE;
 
-        let full_path_cstr = full_path.as_os_str().to_str().unwrap_or("\0").as_ptr() as *const i8;
+    let meta = match fs::metadata(full_path) {
+        Ok(meta) if meta.is_dir() => meta,
+        _ => return false,
+    };
+    let Some(full_path_cstring) = path_cstring(full_path) else {
+        return false;
+    };
+    let full_path_cstr = full_path_cstring.as_ptr();
 
-        let wd = unsafe { fanotify_mark(watch_fd, FLAGS, MASK, AT_FDCWD, full_path_cstr) };
+    let wd = unsafe { fanotify_mark(watch_fd, FLAGS, mark_mask(), AT_FDCWD, full_path_cstr) };
 
-        if wd >= 0 {
-            let _ = mark_set.remove(&wd);
-            true
-        } else {
-            // println!(
-            //     "oops, while unmarking, bad fanotify mark call :: path: {} :: errno: {}",
-            //     unsafe { core::ffi::CStr::from_ptr(full_path_cstr).to_string_lossy() },
-            //     strerrno()
-            // );
-            false
-        }
+    if wd >= 0 {
+        let _ = mark_set.remove(&(meta.dev(), meta.ino()));
+        true
     } else {
+        // println!(
+        //     "oops, while unmarking, bad fanotify mark call :: path: {} :: errno: {}",
+        //     unsafe { core::ffi::CStr::from_ptr(full_path_cstr).to_string_lossy() },
+        //     strerrno()
+        // );
         false
     }
 }
 
-fn check_and_update<'a>(
-    maybe_event: &'a Option<Event>,
-    sr: &'a mut SystemResources,
-) -> &'a Option<Event> {
-    // let (valid, path, what, kind) = r;
+//  Unmarks a directory which has left the tree, and what's below it.
+fn unmark_tree(top: &Path, sr: &mut SystemResources) {
+    let mut dir_queue = VecDeque::<PathBuf>::from([top.to_path_buf()]);
+    while let Some(dir) = dir_queue.pop_front() {
+        unmark_sys(&dir, sr.watch_fd, &mut sr.mark_set);
+        for dirent in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            if dirent.file_type().is_ok_and(|t| t.is_dir()) {
+                dir_queue.push_back(dirent.path());
+            }
+        }
+    }
+}
 
-    if let &Some(event) = &maybe_event {
+//  Whether a path, as the kernel names it, is in the tree below
+//  the root, which might be named some other way.
+fn is_within(path: &Path, root: &Path) -> bool {
+    let root_id = identity(root);
+    root_id.is_some() && path.ancestors().any(|dir| identity(dir) == root_id)
+}
+
+//  Keeps our marks in step with directories coming and going.
+//  A new directory is marked before we look inside of it, so that
+//  nothing made in it goes unseen, and whatever is already in it is
+//  returned as creates, for the caller to send after this event.
+//  A directory moved in from outside the tree is as new to us.
+fn check_and_update(
+    maybe_event: &Option<Event>,
+    sr: &mut SystemResources,
+    options: &WatchOptions,
+) -> Vec<Event> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut found = Vec::new();
+
+    if let Some(event) = maybe_event {
         if event.kind == Kind::Dir {
-            if event.what == What::Create {
-                // println!(
-                //     "trying to mark kind:dir/what:create for path {}",
-                //     (*path).to_string_lossy()
-                // );
-                mark_sys(&event.path, sr.watch_fd, &mut sr.mark_set);
+            let is_new = match event.what {
+                What::Create => true,
+                What::Rename => {
+                    event.associated.is_none()
+                        && fs::metadata(&event.path)
+                            .is_ok_and(|meta| !sr.mark_set.contains(&(meta.dev(), meta.ino())))
+                }
+                _ => false,
+            };
+            if is_new && !options.excludes(&event.path, &event.root) {
+                let mut dir_queue = VecDeque::<PathBuf>::from([event.path.to_path_buf()]);
+                while let Some(dir) = dir_queue.pop_front() {
+                    if !mark_sys(&dir, sr.watch_fd, &mut sr.mark_set) {
+                        continue;
+                    }
+                    for dirent in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                        let path = dirent.path();
+                        let kind = match dirent.file_type().map(|t| t.is_dir()) {
+                            Ok(true) => Kind::Dir,
+                            _ => Kind::File,
+                        };
+                        if kind == Kind::Dir && !options.excludes(&path, &event.root) {
+                            dir_queue.push_back(path.clone());
+                        }
+                        found.push(Event {
+                            path: path.into_boxed_path(),
+                            associated: None,
+                            root: event.root.clone(),
+                            what: What::Create,
+                            kind,
+                            when: now(),
+                        });
+                    }
+                }
             } else if event.what == What::Destroy {
                 // println!(
                 //     "trying to unmark kind:dir/what:create for path {}",
@@ -501,14 +782,18 @@ fn check_and_update<'a>(
                 unmark_sys(&event.path, sr.watch_fd, &mut sr.mark_set);
             }
         }
-
-        maybe_event
-    } else {
-        &None
     }
+
+    found
 }
 
-fn recv(sr: &mut SystemResources, _base_path: &Path, event_tx: SyncSender<Event>) -> bool {
+fn recv(
+    sr: &mut SystemResources,
+    base_path: &Path,
+    options: &WatchOptions,
+    snapshot: &mut Option<Snapshot>,
+    event_tx: EventSender,
+) -> bool {
     use core::ffi::*;
     use libc::read;
     use libc::EAGAIN;
@@ -561,14 +846,17 @@ fn recv(sr: &mut SystemResources, _base_path: &Path, event_tx: SyncSender<Event>
             let readable = |mtd_ptr: *const fanotify_event_metadata, buf_read_len: isize| -> bool {
                 const FAN_EVENT_METADATA_LEN_AS_U32: u32 = 24;
                 const FAN_EVENT_METADATA_LEN_AS_ISIZE: isize = 24;
+                //  Events with file names are padded to 4 bytes,
+                //  not to the 8 bytes the metadata struct aligns to.
+                const FAN_EVENT_ALIGN: u32 = 4;
 
-                let mtd_dref = unsafe { *mtd_ptr };
+                let mtd_dref = unsafe { mtd_ptr.read_unaligned() };
 
                 let buf_read_len_ok = buf_read_len >= FAN_EVENT_METADATA_LEN_AS_ISIZE;
                 let event_len_large_enough = mtd_dref.event_len >= FAN_EVENT_METADATA_LEN_AS_U32;
                 let event_len_fits_in_read_buf =
                     mtd_dref.event_len <= buf_read_len.try_into().unwrap_or(0);
-                let event_len_would_align_next_event = mtd_dref.event_len % 0x8 == 0;
+                let event_len_would_align_next_event = mtd_dref.event_len % FAN_EVENT_ALIGN == 0;
 
                 buf_read_len_ok
                     && event_len_large_enough
@@ -577,18 +865,17 @@ fn recv(sr: &mut SystemResources, _base_path: &Path, event_tx: SyncSender<Event>
             };
 
             let metadata_ok = |mtd_ptr: *const fanotify_event_metadata| -> bool {
-                let mtd_dref = unsafe { *mtd_ptr };
+                let mtd_dref = unsafe { mtd_ptr.read_unaligned() };
                 let ok_no_fd = mtd_dref.fd == FAN_NOFD;
                 let ok_version = mtd_dref.vers == FANOTIFY_METADATA_VERSION;
-                let ok_no_overflow = mtd_dref.mask & FAN_Q_OVERFLOW == 0;
 
-                ok_no_fd && ok_version && ok_no_overflow
+                ok_no_fd && ok_version
             };
 
             let next_event = |mtd_ptr: *mut fanotify_event_metadata,
                               buf_read_len: isize|
              -> (*mut fanotify_event_metadata, isize) {
-                let mtd_dref = unsafe { *mtd_ptr };
+                let mtd_dref = unsafe { mtd_ptr.read_unaligned() };
                 let this_event_len = mtd_dref.event_len;
                 let next_event_read_len = buf_read_len - this_event_len as isize;
                 let next_mtd_ptr =
@@ -598,20 +885,62 @@ fn recv(sr: &mut SystemResources, _base_path: &Path, event_tx: SyncSender<Event>
             };
 
             while readable(mtd, event_read_len) && metadata_ok(mtd) {
-                if let Some(event) = check_and_update(&promote(mtd), sr) {
-                    let send_ok = event_tx.send(event.clone());
-                    let _ = send_ok.map_err(|e| println!("send err: {}", e));
+                let mask = unsafe { mtd.read_unaligned() }.mask;
+                if mask & FAN_Q_OVERFLOW != 0 {
+                    for found in send_overflow(&event_tx, base_path, snapshot, now()) {
+                        if found.kind == Kind::Dir
+                            && found.what == What::Create
+                            && !options.excludes(&found.path, base_path)
+                        {
+                            mark_sys(&found.path, sr.watch_fd, &mut sr.mark_set);
+                        }
+                    }
+                } else if mask & FAN_RENAME != 0 {
+                    if let maybe_event @ Some(event) = &renamed(mtd, base_path) {
+                        let found = check_and_update(maybe_event, sr, options);
+                        send(&event_tx, snapshot, event.clone());
+                        for event in found {
+                            send(&event_tx, snapshot, event);
+                        }
+                    }
+                } else if mask & FAN_MOVE_SELF != 0 {
+                    //  A directory we marked was moved, and its marks went
+                    //  with it. Its parent's mark reports the move itself.
+                    let info = unsafe { mtd.add(1) } as *const fanotify_event_info_fid;
+                    if let Some(dir) = dfid_name_path(info) {
+                        if !is_within(&dir, base_path) {
+                            unmark_tree(&dir, sr);
+                        }
+                    }
+                } else if let maybe_event @ Some(event) = &promote(mtd, base_path) {
+                    //  Without FAN_RENAME, a moved-from and a moved-to
+                    //  could be the halves of one rename, or of two,
+                    //  so each is a rename of its own.
+                    let found = check_and_update(maybe_event, sr, options);
+                    send(&event_tx, snapshot, event.clone());
+                    for event in found {
+                        send(&event_tx, snapshot, event);
+                    }
                 }
                 (mtd, event_read_len) = next_event(mtd, event_read_len);
             }
             true
         }
         State::None => true,
-        State::Err => false,
+        State::Err => {
+            let _s = event_tx.send(Err(WatchError::EventRead(errno())));
+            false
+        }
     }
 }
 
-pub fn watch(path_string: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<bool>) -> bool {
+pub fn watch(
+    path: PathBuf,
+    options: &WatchOptions,
+    event_tx: EventSender,
+    ready_tx: ReadySender,
+    ctl_rx: SyncReceiver<bool>,
+) -> bool {
     use std::sync::mpsc::TryRecvError::Empty;
 
     //  While living, with
@@ -632,13 +961,36 @@ pub fn watch(path_string: String, event_tx: SyncSender<Event>, ctl_rx: SyncRecei
         Err(_) => false,
     };
 
-    let path = Path::new(path_string.as_str());
-    let mut sr = make_system_resources(path);
+    let path = path.as_path();
+    let root_id = identity(path);
+    //  The initial scan is what the marking walk sees.
+    let mut found = Vec::new();
+    let mut sr = make_system_resources(
+        path,
+        options,
+        &event_tx,
+        options.initial_scan.then_some(&mut found),
+    );
     let mut event_recv_list =
         [libc::epoll_event { events: 0, u64: 0 }; EVENT_WAIT_QUEUE_MAX as usize];
-    let event_recv_list_ptr = event_recv_list.as_mut_ptr() as *mut libc::epoll_event;
+    let event_recv_list_ptr = event_recv_list.as_mut_ptr();
+    //  Taken once our marks are in place, so that
+    //  anything which changes after it is an event.
+    let mut snapshot = match sr.valid && options.rescan {
+        true => Some(Snapshot::scan(path, options)),
+        false => None,
+    };
 
-    if sr.valid {
+    if sr.valid && sr.mark_set.is_empty() {
+        close_system_resources(&mut sr);
+        let _s = event_tx.send(Err(WatchError::NoWatches(path.into())));
+        false
+    } else if sr.valid {
+        send_watcher(&event_tx, path, path, What::Create, now());
+        let _s = ready_tx.send((sr.mark_set.len(), std::time::Instant::now()));
+        for event in found {
+            let _s = event_tx.send(Ok(event));
+        }
         while is_living() {
             let event_count = unsafe {
                 libc::epoll_wait(
@@ -650,7 +1002,9 @@ pub fn watch(path_string: String, event_tx: SyncSender<Event>, ctl_rx: SyncRecei
             };
 
             match event_count.cmp(&0) {
+                Ordering::Less if errno() == libc::EINTR => continue,
                 Ordering::Less => {
+                    let _s = event_tx.send(Err(WatchError::EpollWait(errno())));
                     close_system_resources(&mut sr);
                     return false;
                 }
@@ -658,13 +1012,16 @@ pub fn watch(path_string: String, event_tx: SyncSender<Event>, ctl_rx: SyncRecei
                     for n in 0..event_count {
                         let this_event_fd = event_recv_list.index(n as usize).u64;
                         if this_event_fd == sr.watch_fd as u64
-                            && !recv(&mut sr, path, event_tx.clone())
+                            && !recv(&mut sr, path, options, &mut snapshot, event_tx.clone())
                         {
                             close_system_resources(&mut sr);
-                            // println!("e/self/event_recv : {}", strerrno());
                             return false;
                         }
                     }
+                    //  The root was deleted, moved away, or replaced.
+                    if identity(path) != root_id {
+                        break;
+                    }
                 }
                 _ => continue,
             }
@@ -673,7 +1030,6 @@ pub fn watch(path_string: String, event_tx: SyncSender<Event>, ctl_rx: SyncRecei
         close_system_resources(&mut sr)
     } else {
         close_system_resources(&mut sr);
-        // println!("e/self/sys_resource : {}", strerrno());
         false
     }
 }
@@ -1,14 +1,15 @@
+use super::{existing, identity, now, send, send_overflow, send_watcher};
 use crate::watcher::*;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs;
 use std::ops::Index;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::path::PathBuf;
-use std::str::FromStr;
 use std::sync::mpsc::Receiver as SyncReceiver;
-use std::sync::mpsc::Sender as SyncSender;
 
 #[allow(dead_code)]
 pub mod sys {
@@ -20,9 +21,14 @@ pub mod sys {
             use super::super::*;
             pub const IN_CREATE: u32 = 0x00000100;
             pub const IN_MODIFY: u32 = 0x00000002;
+            pub const IN_ATTRIB: u32 = 0x00000004;
             pub const IN_DELETE: u32 = 0x00000200;
             pub const IN_ISDIR: u32 = 0x40000000;
             pub const IN_Q_OVERFLOW: u32 = 0x00004000;
+            pub const IN_IGNORED: u32 = 0x00008000;
+            pub const IN_UNMOUNT: u32 = 0x00002000;
+            pub const IN_DELETE_SELF: u32 = 0x00000400;
+            pub const IN_MOVE_SELF: u32 = 0x00000800;
             pub const IN_MOVED_FROM: u32 = 0x00000040;
             pub const IN_MOVED_TO: u32 = 0x00000080;
             pub const IN_MOVE: u32 = IN_MOVED_FROM | IN_MOVED_TO;
@@ -34,48 +40,69 @@ pub mod sys {
     }
 }
 
-type DirMap = HashMap<i32, String>;
+type DirMap = HashMap<i32, PathBuf>;
 
-fn make_dir_map(base_path: &Path, watch_fd: RawFd) -> DirMap {
+const IN_WATCH_OPT: u32 = {
     use sys::os::linux::*;
-
-    // Follow symlinks, ignore paths which we don't have permissions for.
+    IN_CREATE
+        | IN_MODIFY
+        | IN_ATTRIB
+        | IN_DELETE
+        | IN_MOVED_FROM
+        | IN_MOVED_TO
+        | IN_DELETE_SELF
+        | IN_MOVE_SELF
+        | IN_Q_OVERFLOW
+};
+
+fn make_dir_map(
+    base_path: &Path,
+    options: &WatchOptions,
+    watch_fd: RawFd,
+    event_tx: &EventSender,
+    found: Option<&mut Vec<Event>>,
+) -> DirMap {
     const DIR_MAP_RESERVE_COUNT: usize = 256;
-    const IN_WATCH_OPT: u32 = IN_CREATE | IN_MODIFY | IN_DELETE | IN_MOVED_FROM | IN_Q_OVERFLOW;
 
     let mut pm = DirMap::new();
     pm.reserve(DIR_MAP_RESERVE_COUNT);
 
-    let mut do_mark = |dir: &Path| {
-        if dir.is_dir() {
-            let mut dir_buf: Vec<u8> = dir.to_str().unwrap().as_bytes().to_vec();
-            dir_buf.push(b'\0');
-
-            let wd =
-                unsafe { inotify_add_watch(watch_fd, dir_buf.as_ptr() as *const i8, IN_WATCH_OPT) };
-            if wd >= 0 {
-                // or just > ?
-                let dir_string = dir.to_str().unwrap().to_string();
-                pm.insert(wd, dir_string);
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        }
-    };
+    mark_tree(
+        base_path, base_path, options, watch_fd, event_tx, &mut pm, found,
+    );
+
+    pm
+}
+
+//  Watches `topdir` and every directory below it which isn't excluded.
+fn mark_tree(
+    topdir: &Path,
+    root: &Path,
+    options: &WatchOptions,
+    watch_fd: RawFd,
+    event_tx: &EventSender,
+    pm: &mut DirMap,
+    mut found: Option<&mut Vec<Event>>,
+) {
+    // Follow symlinks, ignore paths which we don't have permissions for.
+    let mut do_mark = |dir: &Path| dir.is_dir() && add_watch(watch_fd, dir, pm, event_tx);
 
+    //  What's only below a symlink isn't reported for the initial scan.
     let mut markwalk_recursive = |topdir: PathBuf| {
-        do_mark(base_path);
-        let mut dirvec = vec![topdir];
+        do_mark(&topdir);
+        let mut dirvec = vec![(topdir, false)];
         'ol: loop {
-            if let Some(nexttop) = dirvec.pop() {
+            if let Some((nexttop, linked)) = dirvec.pop() {
                 if let Ok(mut entries) = fs::read_dir(nexttop) {
                     for entry in entries.by_ref() {
                         if let Ok(dir) = entry {
-                            if do_mark(&dir.path()) {
-                                dirvec.push(dir.path());
+                            let file_type = dir.file_type().ok();
+                            if let Some(found) = found.as_mut().filter(|_| !linked) {
+                                found.push(existing(dir.path(), root, file_type));
+                            }
+                            if !options.excludes(&dir.path(), root) && do_mark(&dir.path()) {
+                                let is_link = file_type.is_some_and(|t| t.is_symlink());
+                                dirvec.push((dir.path(), linked || is_link));
                             }
                         } else {
                             break 'ol;
@@ -90,9 +117,28 @@ fn make_dir_map(base_path: &Path, watch_fd: RawFd) -> DirMap {
         }
     };
 
-    markwalk_recursive(base_path.to_path_buf());
+    markwalk_recursive(topdir.to_path_buf());
+}
 
-    pm
+//  Stops watching `dir` and every directory below it.
+fn unmark_tree(watch_fd: RawFd, dir: &Path, pm: &mut DirMap) {
+    pm.retain(|wd, path| {
+        let within = path.starts_with(dir);
+        if within {
+            unsafe { libc::inotify_rm_watch(watch_fd, *wd) };
+        }
+        !within
+    });
+}
+
+//  The watches below a renamed directory stay with it,
+//  so only the paths we have for them need to change.
+fn rename_tree(from: &Path, to: &Path, pm: &mut DirMap) {
+    for path in pm.values_mut() {
+        if let Ok(below) = path.strip_prefix(from) {
+            *path = to.join(below);
+        }
+    }
 }
 
 /*  @brief wtr/watcher/<d>/adapter/linux/inotify/<a>/fns/system_unfold
@@ -105,9 +151,9 @@ struct SystemResource {
     // event_conf: libc::epoll_event,
 }
 
-fn make_system_resources() -> SystemResource {
-    let do_error = |msg: &str, watch_fd: i32, event_fd: i32| -> SystemResource {
-        println!("{} : {}", msg, strerrno());
+fn make_system_resources(event_tx: &EventSender) -> SystemResource {
+    let do_error = |error: fn(i32) -> WatchError, watch_fd: i32, event_fd: i32| {
+        let _s = event_tx.send(Err(error(errno())));
         SystemResource {
             valid: false,
             watch_fd,
@@ -119,8 +165,7 @@ fn make_system_resources() -> SystemResource {
         }
     };
 
-    let watch_fd = unsafe { libc::inotify_init() };
-    // let watch_fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK) }; // Broken?
+    let watch_fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
 
     if watch_fd >= 0 {
         let mut event_conf = libc::epoll_event {
@@ -141,13 +186,13 @@ fn make_system_resources() -> SystemResource {
                     // event_conf,
                 }
             } else {
-                do_error("e/sys/epoll_ctl", watch_fd, event_fd)
+                do_error(WatchError::EpollCtl, watch_fd, event_fd)
             }
         } else {
-            do_error("e/sys/epoll_create", watch_fd, event_fd)
+            do_error(WatchError::EpollCreate, watch_fd, event_fd)
         }
     } else {
-        do_error("e/sys/inotify_init", watch_fd, -1)
+        do_error(WatchError::InotifyInit, watch_fd, -1)
     }
 }
 
@@ -161,13 +206,6 @@ enum EventRecvState {
     Error,
 }
 
-fn now() -> std::time::Duration {
-    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-        Ok(since_epoch) => since_epoch,
-        Err(_) => std::time::Duration::from_nanos(0),
-    }
-}
-
 #[allow(non_camel_case_types)]
 #[repr(C)]
 pub struct inotify_event {
@@ -178,15 +216,142 @@ pub struct inotify_event {
     pub name: [u8; 0],
 }
 
-fn recv(watch_fd: i32, pm: &mut DirMap, event_tx: SyncSender<Event>) -> bool {
+/*  One half of a rename which is still waiting for its other half.
+When both ends are watched, the kernel queues IN_MOVED_FROM and
+IN_MOVED_TO back-to-back, sharing a cookie. */
+struct PendingMove {
+    cookie: u32,
+    path: PathBuf,
+    kind: Kind,
+}
+
+//  What handling events needs: where they go (the user,
+//  and the snapshot when we keep one), and the watches
+//  which renamed directories take along with them.
+struct Sink<'a> {
+    watch_fd: i32,
+    root: &'a Path,
+    options: &'a WatchOptions,
+    pm: &'a mut DirMap,
+    event_tx: &'a EventSender,
+    snapshot: &'a mut Option<Snapshot>,
+}
+
+impl Sink<'_> {
+    fn send(&mut self, event: Event) {
+        send(self.event_tx, self.snapshot, event);
+    }
+
+    fn send_move(&mut self, from: PathBuf, to: Option<PathBuf>, kind: Kind) {
+        self.send(Event {
+            path: from.into_boxed_path(),
+            associated: to.map(PathBuf::into_boxed_path),
+            root: self.root.into(),
+            what: What::Rename,
+            kind,
+            when: now(),
+        });
+    }
+
+    //  A directory moved within the root keeps its watches,
+    //  unless it was moved somewhere we exclude.
+    fn send_paired_move(&mut self, from: PathBuf, to: PathBuf, kind: Kind) {
+        if kind == Kind::Dir {
+            rename_tree(&from, &to, self.pm);
+            if self.options.excludes(&to, self.root) {
+                unmark_tree(self.watch_fd, &to, self.pm);
+            } else if !self.pm.values().any(|path| *path == to) {
+                self.mark_tree(&to);
+            }
+        }
+        self.send_move(from, Some(to), kind);
+    }
+
+    //  A directory moved out of the root isn't ours anymore.
+    fn send_pending_move(&mut self, pending_move: &mut Option<PendingMove>) {
+        if let Some(pending) = pending_move.take() {
+            if pending.kind == Kind::Dir {
+                unmark_tree(self.watch_fd, &pending.path, self.pm);
+            }
+            self.send_move(pending.path, None, pending.kind);
+        }
+    }
+
+    //  A directory moved in from outside of the root needs watches.
+    fn send_moved_in(&mut self, to: PathBuf, kind: Kind) {
+        if kind == Kind::Dir && !self.options.excludes(&to, self.root) {
+            self.mark_tree(&to);
+        }
+        self.send_move(to, None, kind);
+    }
+
+    //  Anything made in a new directory before we watched it would go
+    //  unseen, so each directory is watched before we look inside of it,
+    //  and we send creates for what we find there.
+    fn mark_created_tree(&mut self, dir: &Path) {
+        let mut dirvec = vec![dir.to_path_buf()];
+        while let Some(dir) = dirvec.pop() {
+            if !add_watch(self.watch_fd, &dir, self.pm, self.event_tx) {
+                continue;
+            }
+            if let Ok(entries) = fs::read_dir(&dir) {
+                for dirent in entries.flatten() {
+                    let path = dirent.path();
+                    let kind = match dirent.file_type().map(|t| t.is_dir()) {
+                        Ok(true) => Kind::Dir,
+                        _ => Kind::File,
+                    };
+                    if kind == Kind::Dir && !self.options.excludes(&path, self.root) {
+                        dirvec.push(path.clone());
+                    }
+                    self.send(Event {
+                        path: path.into_boxed_path(),
+                        associated: None,
+                        root: self.root.into(),
+                        what: What::Create,
+                        kind,
+                        when: now(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn mark_tree(&mut self, dir: &Path) {
+        mark_tree(
+            dir,
+            self.root,
+            self.options,
+            self.watch_fd,
+            self.event_tx,
+            self.pm,
+            None,
+        );
+    }
+}
+
+fn recv(
+    watch_fd: i32,
+    root: &Path,
+    options: &WatchOptions,
+    pm: &mut DirMap,
+    snapshot: &mut Option<Snapshot>,
+    event_tx: EventSender,
+) -> bool {
     use core::ffi::c_void;
+    use core::mem::size_of;
     use sys::os::linux::*;
 
     const EVENT_BUF_LEN: usize = 4096;
-    const IN_WATCH_OPT: u32 = IN_CREATE | IN_MODIFY | IN_DELETE | IN_MOVED_FROM | IN_Q_OVERFLOW;
+    const EVENT_HEAD_LEN: usize = size_of::<inotify_event>();
+
+    // The kernel pads each event so that the next one is aligned,
+    // so aligning the start of the buffer keeps every event aligned.
+    #[repr(C, align(8))]
+    struct EventBuf([u8; EVENT_BUF_LEN]);
 
-    let mut path_buf: [u8; EVENT_BUF_LEN] = [0; EVENT_BUF_LEN];
-    let path_buf_ptr = path_buf.as_mut_ptr();
+    let mut event_buf = EventBuf([0; EVENT_BUF_LEN]);
+    let event_buf_ptr = event_buf.0.as_mut_ptr();
 
     // While inotify has events pending, read them.
     // There might be several events from a single read.
@@ -201,121 +366,204 @@ fn recv(watch_fd: i32, pm: &mut DirMap, event_tx: SyncSender<Event>) -> bool {
     //
     // Forward events and errors to the user.
     //
+    // Renames are held back until we see their other
+    // half, or until we know it isn't coming.
+    //
     // Return when eventless.
 
-    let default_cached_base_path = String::from("");
+    let default_cached_base_path = PathBuf::new();
+
+    let mut pending_move: Option<PendingMove> = None;
 
-    'readloop: loop {
-        let read_len = unsafe { libc::read(watch_fd, path_buf_ptr as *mut c_void, EVENT_BUF_LEN) };
+    let mut unmounted: Vec<PathBuf> = Vec::new();
 
-        let state = match read_len > 0 {
-            true => EventRecvState::Eventful,
-            false => match read_len == 0 {
-                true => match std::io::Error::last_os_error().raw_os_error() {
-                    Some(libc::EAGAIN) => EventRecvState::Eventless,
-                    _ => EventRecvState::Error,
-                },
-                false => EventRecvState::Error,
+    let mut sink = Sink {
+        watch_fd,
+        root,
+        options,
+        pm,
+        event_tx: &event_tx,
+        snapshot,
+    };
+
+    loop {
+        let read_len = unsafe { libc::read(watch_fd, event_buf_ptr as *mut c_void, EVENT_BUF_LEN) };
+
+        let state = match read_len.cmp(&0) {
+            Ordering::Greater => EventRecvState::Eventful,
+            Ordering::Equal => EventRecvState::Eventless,
+            Ordering::Less => match std::io::Error::last_os_error().raw_os_error() {
+                Some(libc::EAGAIN) => EventRecvState::Eventless,
+                _ => EventRecvState::Error,
             },
         };
 
-        let should_continue = match state {
+        match state {
             EventRecvState::Eventful => {
                 /* Loop over all events in the buffer. */
-                let mut this_event_ptr = path_buf_ptr as *const inotify_event;
-
-                while unsafe {
-                    (path_buf_ptr as *const usize).offset_from(this_event_ptr as *const usize)
-                } >= 0
-                {
-                    let this_event = unsafe { &(*this_event_ptr) };
-
-                    if (this_event.mask & IN_Q_OVERFLOW) == 0 {
-                        let cached_base_path =
-                            pm.get(&this_event.wd).unwrap_or(&default_cached_base_path);
-                        let this_event_name_cstr = this_event.name.as_ptr() as *const i8;
-                        let name = unsafe { core::ffi::CStr::from_ptr(this_event_name_cstr) };
-                        let name_str = name.to_str().unwrap();
-                        let mut path_string: String =
-                            String::from_str(cached_base_path).unwrap_or_default();
-                        path_string.push('/');
-                        path_string.push_str(name_str);
-
-                        let kind = match (this_event.mask & IN_ISDIR) != 0 {
-                            true => Kind::Dir,
-                            false => Kind::File,
-                        };
-
-                        let what = match (this_event.mask & IN_CREATE) != 0 {
-                            true => What::Create,
-                            false => match (this_event.mask & IN_DELETE) != 0 {
-                                true => What::Destroy,
-                                false => match (this_event.mask & IN_MOVE) != 0 {
-                                    true => What::Rename,
-                                    false => match (this_event.mask & IN_MODIFY) != 0 {
-                                        true => What::Modify,
+                let mut offset: usize = 0;
+
+                while offset + EVENT_HEAD_LEN <= read_len as usize {
+                    let this_event =
+                        unsafe { &*(event_buf_ptr.add(offset) as *const inotify_event) };
+
+                    offset += EVENT_HEAD_LEN + this_event.len as usize;
+
+                    if (this_event.mask & IN_Q_OVERFLOW) != 0 {
+                        sink.send_pending_move(&mut pending_move);
+                        for found in send_overflow(&event_tx, root, sink.snapshot, now()) {
+                            if found.kind == Kind::Dir
+                                && found.what == What::Create
+                                && !options.excludes(&found.path, root)
+                            {
+                                add_watch(watch_fd, &found.path, sink.pm, &event_tx);
+                            }
+                        }
+                        continue;
+                    }
+
+                    // The filesystem under this directory went away.
+                    // Every watch on it says so, so we wait until we've
+                    // read them all, and tell the user about the topmost.
+                    if (this_event.mask & IN_UNMOUNT) != 0 {
+                        if let Some(dir) = sink.pm.get(&this_event.wd) {
+                            unmounted.push(dir.clone());
+                        }
+                        continue;
+                    }
+
+                    // The kernel dropped this watch, likely because
+                    // its directory is gone. Its parent reported that.
+                    if (this_event.mask & IN_IGNORED) != 0 {
+                        let _v = sink.pm.remove(&this_event.wd);
+                        continue;
+                    }
+
+                    // The parent reports these as a delete or a move of
+                    // one of its entries. We only care when it's the root,
+                    // and the watch loop looks at the root for itself.
+                    if (this_event.mask & (IN_DELETE_SELF | IN_MOVE_SELF)) != 0 {
+                        continue;
+                    }
+
+                    let cached_base_path = sink
+                        .pm
+                        .get(&this_event.wd)
+                        .unwrap_or(&default_cached_base_path);
+                    let this_event_name_cstr = this_event.name.as_ptr() as *const i8;
+                    let name = unsafe { core::ffi::CStr::from_ptr(this_event_name_cstr) };
+                    let name = OsStr::from_bytes(name.to_bytes());
+                    let path = cached_base_path.join(name);
+
+                    let kind = match (this_event.mask & IN_ISDIR) != 0 {
+                        true => Kind::Dir,
+                        false => Kind::File,
+                    };
+
+                    let what = match (this_event.mask & IN_CREATE) != 0 {
+                        true => What::Create,
+                        false => match (this_event.mask & IN_DELETE) != 0 {
+                            true => What::Destroy,
+                            false => match (this_event.mask & IN_MOVE) != 0 {
+                                true => What::Rename,
+                                false => match (this_event.mask & IN_MODIFY) != 0 {
+                                    true => What::Modify,
+                                    false => match (this_event.mask & IN_ATTRIB) != 0 {
+                                        true => What::Owner,
                                         false => What::Other,
                                     },
                                 },
                             },
-                        };
-
-                        let when = now();
-
-                        let path = PathBuf::from_str(&path_string).unwrap().into_boxed_path();
+                        },
+                    };
 
-                        let _s = event_tx.send(Event {
+                    if (this_event.mask & IN_MOVED_FROM) != 0 {
+                        sink.send_pending_move(&mut pending_move);
+                        pending_move = Some(PendingMove {
+                            cookie: this_event.cookie,
                             path,
+                            kind,
+                        });
+                    } else if (this_event.mask & IN_MOVED_TO) != 0 {
+                        match pending_move.take() {
+                            Some(from) if from.cookie == this_event.cookie => {
+                                sink.send_paired_move(from.path, path, kind);
+                            }
+                            unpaired => {
+                                sink.send_pending_move(&mut { unpaired });
+                                sink.send_moved_in(path, kind);
+                            }
+                        }
+                    } else {
+                        sink.send_pending_move(&mut pending_move);
+
+                        sink.send(Event {
+                            path: path.clone().into_boxed_path(),
+                            associated: None,
+                            root: root.into(),
                             what,
                             kind,
-                            when,
+                            when: now(),
                         });
 
-                        if kind == Kind::Dir && what == What::Create {
-                            let new_wd = unsafe {
-                                libc::inotify_add_watch(
-                                    watch_fd,
-                                    this_event_name_cstr,
-                                    IN_WATCH_OPT,
-                                )
-                            };
-                            pm.insert(new_wd, path_string);
-                        } else if kind == Kind::Dir && what == What::Destroy {
-                            unsafe { libc::inotify_rm_watch(watch_fd, this_event.wd) };
-                            let _v = pm.remove(&this_event.wd);
+                        if kind == Kind::Dir
+                            && what == What::Create
+                            && !options.excludes(&path, root)
+                        {
+                            sink.mark_created_tree(&path);
                         }
                     }
-                    // else {
-                    //     println!("e/sys/overflow : {}", strerrno());
-                    // }
-
-                    let next_event_ptr = unsafe { this_event_ptr.add(1) };
-                    this_event_ptr = next_event_ptr;
                 }
-                true
             }
 
-            EventRecvState::Error => false,
-
-            EventRecvState::Eventless => true,
-        };
+            EventRecvState::Eventless => {
+                sink.send_pending_move(&mut pending_move);
+                for dir in &unmounted {
+                    if !unmounted.iter().any(|u| u != dir && dir.starts_with(u)) {
+                        send_watcher(&event_tx, dir, root, What::Unmount, now());
+                    }
+                }
+                return true;
+            }
 
-        if !should_continue {
-            break 'readloop;
+            EventRecvState::Error => {
+                let read_errno = errno();
+                sink.send_pending_move(&mut pending_move);
+                let _s = event_tx.send(Err(WatchError::EventRead(read_errno)));
+                return false;
+            }
         }
     }
+}
 
-    true
+//  Watches a directory which showed up after we started.
+fn add_watch(watch_fd: i32, dir: &Path, pm: &mut DirMap, event_tx: &EventSender) -> bool {
+    let Ok(dir_cstring) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let wd = unsafe { libc::inotify_add_watch(watch_fd, dir_cstring.as_ptr(), IN_WATCH_OPT) };
+    if wd >= 0 {
+        pm.insert(wd, dir.to_path_buf());
+        true
+    } else {
+        if errno() == libc::ENOSPC {
+            let _s = event_tx.send(Err(WatchError::WatchLimit(dir.into())));
+        }
+        false
+    }
 }
 
-fn strerrno() -> String {
-    let errno = unsafe { *libc::__errno_location() };
-    unsafe { core::ffi::CStr::from_ptr(libc::strerror(errno)) }
-        .to_string_lossy()
-        .into_owned()
+fn errno() -> i32 {
+    unsafe { *libc::__errno_location() }
 }
 
-pub fn watch(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<bool>) -> bool {
+pub fn watch(
+    path: PathBuf,
+    options: &WatchOptions,
+    event_tx: EventSender,
+    ready_tx: ReadySender,
+    ctl_rx: SyncReceiver<bool>,
+) -> bool {
     use std::sync::mpsc::TryRecvError::Empty;
 
     //  While living, with
@@ -338,37 +586,71 @@ pub fn watch(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<boo
         Err(_) => false,
     };
 
-    let pb = PathBuf::from(path);
-    let mut sr = make_system_resources();
-    let mut pm = make_dir_map(&pb, sr.watch_fd);
+    let pb = path;
+    let root_id = identity(&pb);
+    //  The initial scan is what the marking walk sees.
+    let mut found = Vec::new();
+    let mut sr = make_system_resources(&event_tx);
+    let mut pm = match sr.valid {
+        true => make_dir_map(
+            &pb,
+            options,
+            sr.watch_fd,
+            &event_tx,
+            options.initial_scan.then_some(&mut found),
+        ),
+        false => DirMap::new(),
+    };
+    //  Taken once our watches are in place, so that
+    //  anything which changes after it is an event.
+    let mut snapshot = match sr.valid && options.rescan {
+        true => Some(Snapshot::scan(&pb, options)),
+        false => None,
+    };
     let mut event_recv_list =
         [libc::epoll_event { events: 0, u64: 0 }; EVENT_WAIT_QUEUE_MAX as usize];
-    let event_recv_list_ptr = event_recv_list.as_mut_ptr() as *mut libc::epoll_event;
+    let event_recv_list_ptr = event_recv_list.as_mut_ptr();
 
     if sr.valid {
         if !pm.is_empty() {
+            send_watcher(&event_tx, &pb, &pb, What::Create, now());
+            let _s = ready_tx.send((pm.len(), std::time::Instant::now()));
+            for event in found {
+                let _s = event_tx.send(Ok(event));
+            }
             while is_living() {
                 let event_count = unsafe {
                     libc::epoll_wait(sr.event_fd, event_recv_list_ptr, EVENT_WAIT_QUEUE_MAX, 16)
                 };
 
                 match event_count.cmp(&0) {
+                    Ordering::Less if errno() == libc::EINTR => continue,
                     Ordering::Less => {
+                        let _s = event_tx.send(Err(WatchError::EpollWait(errno())));
                         close_system_resources(&mut sr);
-                        println!("e/sys/epoll_wait : {}", strerrno());
                         return false;
                     }
                     Ordering::Greater => {
                         for n in 0..event_count {
                             let this_event_fd = event_recv_list.index(n as usize).u64;
                             if this_event_fd == sr.watch_fd as u64
-                                && !recv(sr.watch_fd, &mut pm, event_tx.clone())
+                                && !recv(
+                                    sr.watch_fd,
+                                    &pb,
+                                    options,
+                                    &mut pm,
+                                    &mut snapshot,
+                                    event_tx.clone(),
+                                )
                             {
                                 close_system_resources(&mut sr);
-                                println!("e/self/event_recv : {}", strerrno());
                                 return false;
                             }
                         }
+                        // The root was deleted, moved away, or replaced.
+                        if identity(&pb) != root_id {
+                            break;
+                        }
                     }
                     _ => continue,
                 }
@@ -377,12 +659,11 @@ pub fn watch(path: String, event_tx: SyncSender<Event>, ctl_rx: SyncReceiver<boo
             close_system_resources(&mut sr)
         } else {
             close_system_resources(&mut sr);
-            println!("e/self/path_map : {}", strerrno());
+            let _s = event_tx.send(Err(WatchError::NoWatches(pb.into())));
             false
         }
     } else {
         close_system_resources(&mut sr);
-        println!("e/self/sys_resource : {}", strerrno());
         false
     }
 }
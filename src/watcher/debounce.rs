@@ -0,0 +1,355 @@
+use crate::watcher::*;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio::time::Instant;
+use tokio::time::Sleep;
+use tokio_stream::Stream;
+
+//  What a burst of events on one path comes down to.
+//  Nothing, when something was made and removed within it.
+enum Merged {
+    Into(What),
+    Cancelled,
+    Apart,
+}
+
+fn merge(before: What, after: What) -> Merged {
+    use What::*;
+    match (before, after) {
+        (Create | Exists, Modify | Owner) => Merged::Into(before),
+        (Create | Exists, Destroy) => Merged::Cancelled,
+        (Modify, Modify) | (Owner, Owner) => Merged::Into(before),
+        (Modify | Owner, Destroy) => Merged::Into(Destroy),
+        _ => Merged::Apart,
+    }
+}
+
+//  Renames have two paths, and the watcher's
+//  own events aren't about a path at all.
+fn is_mergeable(event: &Event) -> bool {
+    event.kind != Kind::Watcher && event.associated.is_none() && event.what != What::Rename
+}
+
+//  However busy a path is, nothing is held back for
+//  longer than this many windows from its first event.
+const MAX_HOLD_WINDOWS: u32 = 10;
+
+struct Held {
+    event: Event,
+    //  When it goes out, unless something else on its path comes first.
+    deadline: Instant,
+    //  When it goes out, whatever else comes.
+    until: Instant,
+}
+
+//  The events being held back, and those which are due.
+struct Holding {
+    window: Duration,
+    //  By the order they came in.
+    held: BTreeMap<u64, Held>,
+    next: u64,
+    //  The last held event for each root and path.
+    latest: HashMap<(Box<Path>, Box<Path>), u64>,
+    ready: VecDeque<Event>,
+}
+
+/// An `EventStream` which holds each path's events back until that
+/// path has been quiet for a while, and sends what its burst came
+/// down to: a create and its modifications are a create, a create
+/// and a destroy are nothing, and repeated modifications are one.
+/// A path which never goes quiet has what it came to sent every ten
+/// windows. The watcher's own events go out with whatever came
+/// before them. Errors aren't held back. With no window, events
+/// pass through.
+pub struct Debounced {
+    stream: EventStream,
+    holding: Holding,
+    deadline: Pin<Box<Sleep>>,
+    ended: bool,
+}
+
+impl Debounced {
+    pub fn new(stream: EventStream, window: Duration) -> Debounced {
+        Debounced {
+            stream,
+            holding: Holding::new(window),
+            deadline: Box::pin(sleep(window)),
+            ended: false,
+        }
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        self.stream.roots()
+    }
+
+    pub fn backends(&self) -> &[Backend] {
+        self.stream.backends()
+    }
+
+    pub fn ready(&self) -> impl Future<Output = Ready> + 'static {
+        self.stream.ready()
+    }
+
+    /// Stops the stream underneath. What's held back is
+    /// sent when it ends, ahead of the adapters' last events.
+    pub fn stop(&mut self) -> bool {
+        self.stream.stop()
+    }
+}
+
+impl Holding {
+    fn new(window: Duration) -> Holding {
+        Holding {
+            window,
+            held: BTreeMap::new(),
+            next: 0,
+            latest: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn hold(&mut self, event: Event, now: Instant) {
+        let key = (event.root.clone(), event.path.clone());
+        let before = match is_mergeable(&event) {
+            true => self.latest.get(&key).copied(),
+            false => None,
+        };
+        let merged = before
+            .and_then(|at| Some((at, self.held.get(&at)?.event.what)))
+            .map(|(at, what)| (at, merge(what, event.what)));
+        match merged {
+            Some((at, Merged::Into(what))) => {
+                if let Some(held) = self.held.get_mut(&at) {
+                    held.event.what = what;
+                    held.event.when = event.when;
+                    held.deadline = (now + self.window).min(held.until);
+                }
+            }
+            Some((at, Merged::Cancelled)) => {
+                self.held.remove(&at);
+                self.latest.remove(&key);
+            }
+            _ => {
+                if is_mergeable(&event) {
+                    self.latest.insert(key, self.next);
+                } else {
+                    //  Whatever came before a rename of these paths
+                    //  stays apart from whatever comes after it.
+                    self.latest.remove(&key);
+                    if let Some(to) = &event.associated {
+                        self.latest.remove(&(event.root.clone(), to.clone()));
+                    }
+                }
+                let until = now + self.window * MAX_HOLD_WINDOWS;
+                let deadline = now + self.window;
+                self.held.insert(
+                    self.next,
+                    Held {
+                        event,
+                        deadline,
+                        until,
+                    },
+                );
+                self.next += 1;
+            }
+        }
+    }
+
+    //  Sends on what's due, in the order it came in. The watcher's
+    //  own events take everything which came before them along.
+    fn release(&mut self, now: Instant) {
+        let barrier = self
+            .held
+            .iter()
+            .rev()
+            .find(|(_, held)| held.event.kind == Kind::Watcher && held.deadline <= now)
+            .map(|(at, _)| *at);
+        let due: Vec<u64> = self
+            .held
+            .iter()
+            .filter(|(at, held)| held.deadline <= now || barrier.is_some_and(|b| **at <= b))
+            .map(|(at, _)| *at)
+            .collect();
+        for at in due {
+            if let Some(held) = self.held.remove(&at) {
+                let key = (held.event.root.clone(), held.event.path.clone());
+                if self.latest.get(&key) == Some(&at) {
+                    self.latest.remove(&key);
+                }
+                self.ready.push_back(held.event);
+            }
+        }
+    }
+
+    fn release_all(&mut self) {
+        self.latest.clear();
+        self.ready.extend(
+            std::mem::take(&mut self.held)
+                .into_values()
+                .map(|held| held.event),
+        );
+    }
+
+    fn first_deadline(&self) -> Option<Instant> {
+        self.held.values().map(|held| held.deadline).min()
+    }
+}
+
+impl Stream for Debounced {
+    type Item = Result<Event, WatchError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.holding.window.is_zero() {
+            return Pin::new(&mut self.stream).poll_next(cx);
+        }
+
+        loop {
+            if let Some(event) = self.holding.ready.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if self.ended {
+                return Poll::Ready(None);
+            }
+
+            /*  When the stream ends, there's nothing left to wait for. */
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => {
+                    self.holding.hold(event, Instant::now());
+                    continue;
+                }
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error))),
+                Poll::Ready(None) => {
+                    self.holding.release_all();
+                    self.ended = true;
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+
+            self.holding.release(Instant::now());
+            if !self.holding.ready.is_empty() {
+                continue;
+            }
+            /*  Wake up for whichever path is due first. */
+            let Some(first) = self.holding.first_deadline() else {
+                return Poll::Pending;
+            };
+            self.deadline.as_mut().reset(first);
+            match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => continue,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(path: &str, what: What, kind: Kind) -> Event {
+        Event {
+            path: Path::new(path).into(),
+            associated: None,
+            root: Path::new("/w").into(),
+            what,
+            kind,
+            when: Duration::from_nanos(0),
+        }
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    //  What's been let out so far, as what happened to which path.
+    fn released(holding: &mut Holding) -> Vec<String> {
+        holding
+            .ready
+            .drain(..)
+            .map(|event| format!("{} {}", event.what, event.path.display()))
+            .collect()
+    }
+
+    #[test]
+    fn merges() {
+        use What::*;
+        assert!(matches!(merge(Create, Modify), Merged::Into(Create)));
+        assert!(matches!(merge(Create, Owner), Merged::Into(Create)));
+        assert!(matches!(merge(Exists, Modify), Merged::Into(Exists)));
+        assert!(matches!(merge(Modify, Modify), Merged::Into(Modify)));
+        assert!(matches!(merge(Modify, Destroy), Merged::Into(Destroy)));
+        assert!(matches!(merge(Owner, Destroy), Merged::Into(Destroy)));
+        assert!(matches!(merge(Create, Destroy), Merged::Cancelled));
+        assert!(matches!(merge(Exists, Destroy), Merged::Cancelled));
+        assert!(matches!(merge(Destroy, Create), Merged::Apart));
+        assert!(matches!(merge(Modify, Owner), Merged::Apart));
+    }
+
+    #[test]
+    fn bursts() {
+        let start = Instant::now();
+        let mut holding = Holding::new(ms(100));
+
+        holding.hold(event("/w/a", What::Create, Kind::File), start);
+        holding.hold(event("/w/a", What::Modify, Kind::File), start + ms(50));
+        holding.hold(event("/w/b", What::Create, Kind::File), start + ms(60));
+        holding.hold(event("/w/b", What::Destroy, Kind::File), start + ms(70));
+        holding.hold(event("/w/c", What::Modify, Kind::File), start + ms(80));
+        holding.hold(event("/w/c", What::Destroy, Kind::File), start + ms(90));
+
+        // Each path's window starts over with each of its events.
+        holding.release(start + ms(120));
+        assert!(released(&mut holding).is_empty());
+        holding.release(start + ms(150));
+        assert_eq!(released(&mut holding), ["create /w/a"]);
+        holding.release(start + ms(190));
+        assert_eq!(released(&mut holding), ["destroy /w/c"]);
+        assert!(holding.held.is_empty());
+    }
+
+    #[test]
+    fn busy_paths_go_out_anyway() {
+        let start = Instant::now();
+        let mut holding = Holding::new(ms(100));
+
+        let mut sent = Vec::new();
+        for n in 0..50 {
+            let now = start + ms(n * 50);
+            holding.hold(event("/w/log", What::Modify, Kind::File), now);
+            holding.release(now);
+            if !released(&mut holding).is_empty() {
+                sent.push(n * 50);
+            }
+        }
+        // The next burst starts with the event after the last one sent.
+        assert_eq!(sent, [1000, 2050]);
+    }
+
+    #[test]
+    fn watcher_events_keep_their_place() {
+        let start = Instant::now();
+        let mut holding = Holding::new(ms(100));
+
+        holding.hold(event("/w/a", What::Modify, Kind::File), start);
+        holding.hold(event("/w", What::Destroy, Kind::Watcher), start + ms(10));
+        holding.hold(event("/w/b", What::Modify, Kind::File), start + ms(20));
+        holding.hold(event("/w/a", What::Modify, Kind::File), start + ms(90));
+
+        // What came before the watcher's event goes out with it,
+        // however recently it was added to, and what came after waits.
+        holding.release(start + ms(110));
+        assert_eq!(released(&mut holding), ["modify /w/a", "destroy /w"]);
+        holding.release(start + ms(120));
+        assert_eq!(released(&mut holding), ["modify /w/b"]);
+    }
+}
@@ -0,0 +1,49 @@
+use std::{fmt, io, path::Path};
+
+/// Something which went wrong while watching.
+/// System call failures carry the `errno` they left behind.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WatchError {
+    InotifyInit(i32),
+    FanotifyInit(i32),
+    EpollCreate(i32),
+    EpollCtl(i32),
+    EpollWait(i32),
+    EventRead(i32),
+    /// We ran out of watches (or marks) while adding this path.
+    /// Usually solved by raising `fs.inotify.max_user_watches`.
+    WatchLimit(Box<Path>),
+    /// Nothing under this path could be watched.
+    NoWatches(Box<Path>),
+}
+
+fn strerror(errno: i32) -> String {
+    io::Error::from_raw_os_error(errno).to_string()
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchError::InotifyInit(errno) => {
+                write!(f, "e/sys/inotify_init : {}", strerror(*errno))
+            }
+            WatchError::FanotifyInit(errno) => {
+                write!(f, "e/sys/fanotify_init : {}", strerror(*errno))
+            }
+            WatchError::EpollCreate(errno) => {
+                write!(f, "e/sys/epoll_create : {}", strerror(*errno))
+            }
+            WatchError::EpollCtl(errno) => write!(f, "e/sys/epoll_ctl : {}", strerror(*errno)),
+            WatchError::EpollWait(errno) => write!(f, "e/sys/epoll_wait : {}", strerror(*errno)),
+            WatchError::EventRead(errno) => write!(f, "e/sys/read : {}", strerror(*errno)),
+            WatchError::WatchLimit(path) => {
+                write!(f, "e/sys/watch_limit : {}", path.to_string_lossy())
+            }
+            WatchError::NoWatches(path) => {
+                write!(f, "e/self/path_map : {}", path.to_string_lossy())
+            }
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
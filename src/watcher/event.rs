@@ -3,6 +3,12 @@ use std::{fmt, path::Path, time::Duration};
 #[derive(Clone, Eq, PartialEq)]
 pub struct Event {
     pub path: Box<Path>,
+    /// The other side of a rename, when the backend could pair
+    /// both halves of it. `path` is where the file was moved
+    /// from, and this is where it was moved to.
+    pub associated: Option<Box<Path>>,
+    /// The watched path this event came from.
+    pub root: Box<Path>,
     pub what: What,
     pub kind: Kind,
     pub when: Duration,
@@ -13,19 +19,120 @@ impl Event {
     pub fn is_last(&self) -> bool {
         self.what == What::Destroy && self.kind == Kind::Watcher
     }
+
+    pub fn json(&self) -> Json<'_> {
+        Json(self, false)
+    }
+
+    /// The event's path below its watch root,
+    /// or nothing if the path isn't below it.
+    pub fn relative_path(&self) -> Option<&Path> {
+        self.path.strip_prefix(&self.root).ok()
+    }
+
+    /// Whether `is_match` holds for the event's path, or for where
+    /// it was renamed to, either as-is or relative to its watch root.
+    pub fn path_matches(&self, is_match: impl Fn(&Path) -> bool) -> bool {
+        let matches = |path: &Path| {
+            is_match(path)
+                || path
+                    .strip_prefix(&self.root)
+                    .map(&is_match)
+                    .unwrap_or(false)
+        };
+        matches(&self.path) || self.associated.as_deref().map(matches).unwrap_or(false)
+    }
+}
+
+/// Escapes `s` for use inside a JSON string.
+/// Quotes, backslashes and control characters are escaped,
+/// everything else is passed through as-is.
+pub fn escape_json(s: &str) -> String {
+    use fmt::Write;
+
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a path for use inside a JSON string, like `escape_json`.
+/// Paths are bytes, and JSON strings can't hold bytes which aren't
+/// UTF-8, so each of those is written as a lone surrogate: the byte
+/// 0xNN becomes `\udcNN`. This is what Python calls "surrogateescape",
+/// and it can't be confused with a real character.
+pub fn escape_json_path(path: &Path) -> String {
+    use fmt::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut escaped = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        escaped.push_str(&escape_json(chunk.valid()));
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\udc{:02x}", byte);
+        }
+    }
+    escaped
+}
+
+/// An event as a single, complete JSON object.
+/// The watch root is left out unless asked for with `with_root`.
+/// `when` is in nanoseconds, as a string: there are more of them
+/// than a JSON number can be trusted to hold exactly.
+pub struct Json<'a>(&'a Event, bool);
+
+impl Json<'_> {
+    pub fn with_root(self) -> Self {
+        Json(self.0, true)
+    }
+}
+
+impl fmt::Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let event = self.0;
+        write!(
+            f,
+            r#"{{"when":"{}","where":"{}","what":"{}","kind":"{}""#,
+            event.when.as_nanos(),
+            escape_json_path(&event.path),
+            event.what,
+            event.kind,
+        )?;
+        if let Some(associated) = &event.associated {
+            write!(f, r#","associated":"{}""#, escape_json_path(associated))?;
+        }
+        if self.1 {
+            write!(f, r#","root":"{}""#, escape_json_path(&event.root))?;
+        }
+        write!(f, "}}")
+    }
 }
 
 impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            r#""{}":{{"where":"{}","what":"{}","kind":"{}"}}{}"#,
+            r#""{}":{{"where":"{}","what":"{}","kind":"{}""#,
             self.when.as_nanos(),
-            self.path.to_string_lossy().replace('\0', ""),
+            escape_json_path(&self.path),
             self.what,
             self.kind,
-            if self.is_last() { "" } else { "," },
-        )
+        )?;
+        if let Some(associated) = &self.associated {
+            write!(f, r#","associated":"{}""#, escape_json_path(associated))?;
+        }
+        write!(f, "}}{}", if self.is_last() { "" } else { "," })
     }
 }
 
@@ -37,6 +144,16 @@ pub enum What {
     Create,
     Destroy,
     Owner,
+    /// The kernel dropped events for the watch root.
+    /// Seen with `Kind::Watcher`.
+    Overflow,
+    /// The path was there when we started watching.
+    /// Sent for everything below the root, before anything else,
+    /// when asked for an initial scan.
+    Exists,
+    /// The filesystem below the path was unmounted.
+    /// Seen with `Kind::Watcher`.
+    Unmount,
     Other,
 }
 
@@ -59,6 +176,9 @@ impl<'a> From<&'a str> for What {
             "create" => What::Create,
             "destroy" => What::Destroy,
             "owner" => What::Owner,
+            "overflow" => What::Overflow,
+            "unmount" => What::Unmount,
+            "exists" => What::Exists,
             "other" => What::Other,
             _ => What::Other,
         }
@@ -79,6 +199,9 @@ impl fmt::Display for What {
             What::Create => write!(f, "create"),
             What::Destroy => write!(f, "destroy"),
             What::Owner => write!(f, "owner"),
+            What::Overflow => write!(f, "overflow"),
+            What::Unmount => write!(f, "unmount"),
+            What::Exists => write!(f, "exists"),
             What::Other => write!(f, "other"),
         }
     }
@@ -123,3 +246,63 @@ impl fmt::Display for Kind {
 
 // pub type Callback = dyn Fn(Event) + Send + Sync;
 // pub trait Callback: Fn(Event) + Send + Sync + 'static{}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renamed(from: &str, to: &str) -> Event {
+        Event {
+            path: Path::new(from).into(),
+            associated: Some(Path::new(to).into()),
+            root: Path::new("/w").into(),
+            what: What::Rename,
+            kind: Kind::File,
+            when: Duration::from_nanos(0),
+        }
+    }
+
+    #[test]
+    fn renames_match_on_either_side() {
+        let is_rs = |p: &Path| p.extension().is_some_and(|e| e == "rs");
+        let is_src = |p: &Path| p.starts_with("src");
+
+        assert!(renamed("/w/src/.main.rs.swp", "/w/src/main.rs").path_matches(is_rs));
+        assert!(renamed("/w/src/main.rs", "/w/src/main.rs~").path_matches(is_rs));
+        assert!(renamed("/w/src/a.swp", "/w/b.swp").path_matches(is_src));
+        assert!(!renamed("/w/a.swp", "/w/b.swp").path_matches(is_rs));
+    }
+
+    #[test]
+    fn escapes_json() {
+        assert_eq!(escape_json(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape_json(r"C:\dir\"), r"C:\\dir\\");
+        assert_eq!(escape_json("a\nb\rc\td"), r"a\nb\rc\td");
+        assert_eq!(escape_json("\u{0}\u{1b}\u{7f}"), r"\u0000\u001b\u007f");
+        assert_eq!(escape_json("café ✓"), "café ✓");
+    }
+
+    #[test]
+    fn escapes_paths_which_arent_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = |bytes: &[u8]| escape_json_path(Path::new(OsStr::from_bytes(bytes)));
+
+        assert_eq!(path(b"/w/caf\xe9"), r"/w/caf\udce9");
+        assert_eq!(path(b"/w/caf\xc3\xa9"), "/w/café");
+        assert_eq!(path(b"\xff\xfe/x"), r"\udcff\udcfe/x");
+        assert_eq!(path(b"/w/\"\xe9\\\n"), r#"/w/\"\udce9\\\n"#);
+    }
+
+    #[test]
+    fn when_is_exact() {
+        let event = Event {
+            when: Duration::from_nanos(1_792_312_423_418_188_667),
+            ..renamed("/w/a", "/w/b")
+        };
+        assert_eq!(
+            event.json().with_root().to_string(),
+            r#"{"when":"1792312423418188667","where":"/w/a","what":"rename","kind":"file","associated":"/w/b","root":"/w"}"#
+        );
+    }
+}
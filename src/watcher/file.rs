@@ -0,0 +1,123 @@
+use crate::watcher::*;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
+
+//  Editors save by writing a new file and renaming it over the old
+//  one, or by moving the old one out of the way and writing a new one.
+//  When the file goes away, we give it this long to come back before
+//  we believe it's gone.
+const SAVE_WINDOW: Duration = Duration::from_millis(50);
+
+enum Change {
+    Arrived,
+    Replaced,
+    Left,
+    Changed,
+    Unrelated,
+}
+
+fn change(file: &Path, event: &Event) -> Change {
+    let is_from = *event.path == *file;
+    let is_to = event.associated.as_deref() == Some(file);
+    match event.what {
+        _ if event.kind == Kind::Watcher => Change::Changed,
+        What::Rename if is_to => Change::Replaced,
+        //  The other half of this rename wasn't seen, so
+        //  we can't tell if the file was moved here or away.
+        What::Rename if is_from && event.associated.is_none() => match file.exists() {
+            true => Change::Replaced,
+            false => Change::Left,
+        },
+        What::Rename | What::Destroy if is_from => Change::Left,
+        What::Create if is_from => Change::Arrived,
+        _ if is_from => Change::Changed,
+        _ => Change::Unrelated,
+    }
+}
+
+fn about(file: &Path, event: Event) -> Event {
+    let path = match event.kind {
+        Kind::Watcher => file.into(),
+        _ => event.path,
+    };
+    Event {
+        path,
+        root: file.into(),
+        ..event
+    }
+}
+
+fn modified(file: &Path, when: Duration) -> Event {
+    Event {
+        path: file.into(),
+        associated: None,
+        root: file.into(),
+        what: What::Modify,
+        kind: Kind::File,
+        when,
+    }
+}
+
+/// Passes on the events about `file` from an adapter watching its
+/// parent directory, as if the file were the root. When the file is
+/// replaced by a rename, or goes away and comes right back, that's
+/// sent as a modification.
+pub async fn follow(
+    file: PathBuf,
+    mut event_rx: UnboundedReceiver<Result<Event, WatchError>>,
+    event_tx: EventSender,
+) -> bool {
+    let mut gone: Option<(Event, Instant)> = None;
+
+    loop {
+        let next = match &gone {
+            Some((_, deadline)) => match timeout_at(*deadline, event_rx.recv()).await {
+                Ok(next) => next,
+                Err(_elapsed) => {
+                    if let Some((event, _)) = gone.take() {
+                        let _s = event_tx.send(Ok(event));
+                    }
+                    continue;
+                }
+            },
+            None => event_rx.recv().await,
+        };
+
+        let event = match next {
+            Some(Ok(event)) => event,
+            Some(Err(error)) => {
+                let _s = event_tx.send(Err(error));
+                continue;
+            }
+            None => break,
+        };
+
+        let event = match change(&file, &event) {
+            Change::Unrelated => continue,
+            Change::Left => gone
+                .replace((about(&file, event), Instant::now() + SAVE_WINDOW))
+                .map(|(event, _)| event),
+            Change::Arrived | Change::Replaced if gone.take().is_some() => {
+                Some(modified(&file, event.when))
+            }
+            Change::Replaced => Some(modified(&file, event.when)),
+            Change::Arrived | Change::Changed => Some(about(&file, event)),
+        };
+
+        if let Some(event) = event {
+            if event_tx.send(Ok(event)).is_err() {
+                break;
+            }
+        }
+    }
+
+    if let Some((event, _)) = gone {
+        let _s = event_tx.send(Ok(event));
+    }
+
+    true
+}
@@ -0,0 +1,249 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+//  A shell-style pattern, matched against the raw bytes of a path,
+//  so file names which aren't valid UTF-8 match like any other.
+//
+//  *       anything but a '/'
+//  **      anything, as a whole path component ("a/**", "**/b")
+//  ?       one byte which isn't a '/'
+//  [a-z]   one byte in the class, or not in it with [!a-z] or [^a-z]
+//  \c      the byte c, as-is
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Byte(u8),
+    One,
+    Class(bool, Vec<(u8, u8)>),
+    Star,
+    AnyDepth,
+    AnyDirs,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glob {
+    pattern: String,
+    tokens: Vec<Token>,
+}
+
+fn parse_class(pattern: &[u8], at: usize) -> Option<(Token, usize)> {
+    let mut i = at + 1;
+    let negated = matches!(pattern.get(i), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    while let Some(&c) = pattern.get(i) {
+        if c == b']' && !first {
+            return Some((Token::Class(negated, ranges), i + 1));
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(&hi)) if hi != b']' => {
+                ranges.push((c, hi));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+fn tokenize(pattern: &[u8]) -> Vec<Token> {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'*' if pattern.get(i + 1) == Some(&b'*') => {
+                let at_start = i == 0 || pattern[i - 1] == b'/';
+                let next = pattern.get(i + 2);
+                if at_start && next == Some(&b'/') {
+                    tokens.push(Token::AnyDirs);
+                    i += 3;
+                } else if at_start && next.is_none() {
+                    tokens.push(Token::AnyDepth);
+                    i += 2;
+                } else {
+                    tokens.push(Token::Star);
+                    i += 2;
+                }
+            }
+            b'*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            b'?' => {
+                tokens.push(Token::One);
+                i += 1;
+            }
+            b'[' => match parse_class(pattern, i) {
+                Some((class, next)) => {
+                    tokens.push(class);
+                    i = next;
+                }
+                None => {
+                    tokens.push(Token::Byte(b'['));
+                    i += 1;
+                }
+            },
+            b'\\' if i + 1 < pattern.len() => {
+                tokens.push(Token::Byte(pattern[i + 1]));
+                i += 2;
+            }
+            c => {
+                tokens.push(Token::Byte(c));
+                i += 1;
+            }
+        }
+    }
+    tokens
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob {
+            pattern: pattern.to_string(),
+            tokens: tokenize(pattern.as_bytes()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.is_match_bytes(path.as_os_str().as_bytes())
+    }
+
+    //  One pass per token over "which prefixes of the text
+    //  could the pattern so far have matched".
+    pub fn is_match_bytes(&self, text: &[u8]) -> bool {
+        let mut matched = vec![false; text.len() + 1];
+        let mut next = vec![false; text.len() + 1];
+        matched[0] = true;
+
+        for token in &self.tokens {
+            let mut any_before = false;
+            next[0] = match token {
+                Token::Star | Token::AnyDepth | Token::AnyDirs => matched[0],
+                _ => false,
+            };
+            for j in 1..=text.len() {
+                let c = text[j - 1];
+                any_before |= matched[j - 1];
+                next[j] = match token {
+                    Token::Byte(b) => matched[j - 1] && c == *b,
+                    Token::One => matched[j - 1] && c != b'/',
+                    Token::Class(negated, ranges) => {
+                        let within = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                        matched[j - 1] && c != b'/' && within != *negated
+                    }
+                    Token::Star => matched[j] || (next[j - 1] && c != b'/'),
+                    Token::AnyDepth => matched[j] || next[j - 1],
+                    Token::AnyDirs => matched[j] || (c == b'/' && any_before),
+                };
+            }
+            std::mem::swap(&mut matched, &mut next);
+        }
+
+        matched[text.len()]
+    }
+}
+
+impl<'a> From<&'a str> for Glob {
+    fn from(s: &'a str) -> Glob {
+        Glob::new(s)
+    }
+}
+
+impl From<String> for Glob {
+    fn from(s: String) -> Glob {
+        Glob::new(s.as_str())
+    }
+}
+
+impl std::fmt::Display for Glob {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Glob::new(pattern).is_match(Path::new(text))
+    }
+
+    #[test]
+    fn any_dirs_then_name() {
+        assert!(matches("**/*.rs", "main.rs"));
+        assert!(matches("**/*.rs", "src/main.rs"));
+        assert!(matches("**/*.rs", "src/watcher/filter.rs"));
+        assert!(!matches("**/*.rs", "src/main.rsx"));
+        assert!(!matches("**/*.rs", "src/main.rs/x"));
+    }
+
+    #[test]
+    fn any_depth_below() {
+        assert!(matches("src/**", "src/main.rs"));
+        assert!(matches("src/**", "src/watcher/filter.rs"));
+        assert!(!matches("src/**", "srcs/main.rs"));
+        assert!(!matches("src/**", "lib/src/main.rs"));
+    }
+
+    #[test]
+    fn any_dirs_between() {
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "a/xb"));
+        assert!(!matches("a/**/b", "ab"));
+    }
+
+    #[test]
+    fn star_stays_in_one_component() {
+        assert!(matches("*", "main.rs"));
+        assert!(!matches("*", "src/main.rs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/watcher/filter.rs"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[^a-c]x", "dx"));
+        assert!(!matches("[!a-c]x", "ax"));
+        assert!(!matches("a[!x]b", "a/b"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        // An unclosed class is a '['.
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "a"));
+        assert!(matches(r"a\?", "a?"));
+        assert!(!matches(r"a\?", "ab"));
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let name = Path::new(OsStr::from_bytes(b"dir/caf\xe9.txt"));
+        assert!(Glob::new("**/*.txt").is_match(name));
+        assert!(Glob::new("dir/caf?.txt").is_match(name));
+        assert!(Glob::new("dir/caf[!a-z].txt").is_match(name));
+        assert!(!Glob::new("dir/cafe.txt").is_match(name));
+    }
+}
@@ -0,0 +1,336 @@
+use crate::watcher::*;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//  The files we read rules from, lowest precedence first.
+//  Rules from deeper directories win over shallower ones,
+//  and `.ignore` wins over `.gitignore` in the same directory.
+const GIT_EXCLUDE: &str = ".git/info/exclude";
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+struct Rule {
+    base: PathBuf,
+    glob: Glob,
+    negated: bool,
+    dir_only: bool,
+}
+
+fn parse_rule(base: &Path, line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    //  A pattern with a slash is relative to its file's directory.
+    //  Without one, it matches a name at any depth below it.
+    let pattern = match line.contains('/') {
+        true => line.trim_start_matches('/').to_string(),
+        false => format!("**/{}", line),
+    };
+    Some(Rule {
+        base: base.to_path_buf(),
+        glob: Glob::new(&pattern),
+        negated,
+        dir_only,
+    })
+}
+
+/// The `.gitignore`, `.ignore` and `.git/info/exclude` rules under a root.
+pub struct GitIgnore {
+    root: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl GitIgnore {
+    pub fn load(root: &Path) -> GitIgnore {
+        let mut gitignore = GitIgnore {
+            root: root.to_path_buf(),
+            rules: Vec::new(),
+        };
+
+        gitignore.read_rules(root, &root.join(GIT_EXCLUDE));
+
+        let mut dir_queue = VecDeque::<PathBuf>::from([root.to_path_buf()]);
+
+        while let Some(dir) = dir_queue.pop_front() {
+            for name in IGNORE_FILES {
+                gitignore.read_rules(&dir, &dir.join(name));
+            }
+            if let Ok(entries) = fs::read_dir(&dir) {
+                for dirent in entries.flatten() {
+                    let path = dirent.path();
+                    let is_dir = dirent.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                    if is_dir && !gitignore.is_ignored(&path, true) {
+                        dir_queue.push_back(path);
+                    }
+                }
+            }
+        }
+
+        gitignore
+    }
+
+    fn read_rules(&mut self, base: &Path, file: &Path) {
+        if let Ok(contents) = fs::read(file) {
+            let contents = String::from_utf8_lossy(&contents);
+            self.rules
+                .extend(contents.lines().filter_map(|line| parse_rule(base, line)));
+        }
+    }
+
+    /// Whether an event on this path could change the rules.
+    pub fn is_rule_file(path: &Path) -> bool {
+        path.ends_with(GIT_EXCLUDE)
+            || path
+                .file_name()
+                .map(|name| IGNORE_FILES.iter().any(|f| name == *f))
+                .unwrap_or(false)
+    }
+
+    //  The last rule which matches decides.
+    fn last_match(&self, path: &Path, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .filter(|rule| is_dir || !rule.dir_only)
+            .find(|rule| {
+                path.strip_prefix(&rule.base)
+                    .map(|rel| rule.glob.is_match(rel))
+                    .unwrap_or(false)
+            })
+            .map(|rule| !rule.negated)
+            .unwrap_or(false)
+    }
+
+    /// Whether the path, or any directory between it and the root, is ignored.
+    /// Git never looks at what's inside of `.git`, so neither do we.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        rel.ancestors()
+            .filter(|a| !a.as_os_str().is_empty())
+            .any(|a| {
+                let a_is_dir = is_dir || a != rel;
+                (a_is_dir && a.ends_with(".git")) || self.last_match(&self.root.join(a), a_is_dir)
+            })
+    }
+}
+
+/// Whether any of the globs match the path, or a directory
+/// between it and the root.
+pub fn is_excluded(globs: &[Glob], path: &Path, root: &Path) -> bool {
+    let rel = path.strip_prefix(root).ok();
+    let mut prefixes = rel
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .filter(|a| !a.as_os_str().is_empty());
+    prefixes.any(|a| {
+        let abs = root.join(a);
+        globs
+            .iter()
+            .any(|glob| glob.is_match(a) || glob.is_match(&abs))
+    }) || globs.iter().any(|glob| glob.is_match(path))
+}
+
+/// Paths we don't want to hear about.
+/// Each glob ignores the paths it matches and everything below them.
+/// Globs match like `--filter-path` does, against the absolute path
+/// and the path below the watch root.
+pub struct Ignore {
+    globs: Vec<Glob>,
+    gitignores: Vec<GitIgnore>,
+}
+
+impl Ignore {
+    pub fn new(globs: Vec<Glob>) -> Ignore {
+        Ignore {
+            globs,
+            gitignores: Vec::new(),
+        }
+    }
+
+    /// Also ignore what git would, for each of these roots.
+    pub fn respect_gitignore(mut self, roots: &[PathBuf]) -> Ignore {
+        self.gitignores = roots.iter().map(|root| GitIgnore::load(root)).collect();
+        self
+    }
+
+    pub fn is_ignored(&self, path: &Path, root: &Path, is_dir: bool) -> bool {
+        let glob_ignored = || is_excluded(&self.globs, path, root);
+        let git_ignored = || {
+            self.gitignores
+                .iter()
+                .filter(|gitignore| gitignore.root == root)
+                .any(|gitignore| gitignore.is_ignored(path, is_dir))
+        };
+        glob_ignored() || git_ignored()
+    }
+
+    /// A rename is only ignored when both of its sides are.
+    pub fn is_event_ignored(&self, event: &Event) -> bool {
+        let is_dir = event.kind == Kind::Dir;
+        self.is_ignored(&event.path, &event.root, is_dir)
+            && event
+                .associated
+                .as_deref()
+                .map(|to| self.is_ignored(to, &event.root, is_dir))
+                .unwrap_or(true)
+    }
+
+    /// Reloads the git rules for the event's root if the event
+    /// touched one of the files they come from.
+    pub fn update(&mut self, event: &Event) {
+        let touched = GitIgnore::is_rule_file(&event.path)
+            || event
+                .associated
+                .as_deref()
+                .map(GitIgnore::is_rule_file)
+                .unwrap_or(false);
+        if touched {
+            for gitignore in self.gitignores.iter_mut() {
+                if *gitignore.root == *event.root {
+                    *gitignore = GitIgnore::load(&event.root);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //  A directory of its own for each test, with these files in it.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str, files: &[(&str, &str)]) -> Fixture {
+            let root = std::env::temp_dir().join(format!(
+                "watcher-ignore-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root);
+            for (path, contents) in files {
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            Fixture(root)
+        }
+
+        fn ignored(&self, gitignore: &GitIgnore, path: &str, is_dir: bool) -> bool {
+            gitignore.is_ignored(&self.0.join(path), is_dir)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn dirs_names_and_reincludes() {
+        let fixture = Fixture::new("basic", &[(".gitignore", "logs/\n*.tmp\n!keep.tmp\n")]);
+        let gitignore = GitIgnore::load(&fixture.0);
+
+        assert!(fixture.ignored(&gitignore, "logs", true));
+        assert!(fixture.ignored(&gitignore, "logs/today.txt", false));
+        assert!(fixture.ignored(&gitignore, "sub/logs", true));
+        // "logs/" is only for directories.
+        assert!(!fixture.ignored(&gitignore, "logs", false));
+
+        assert!(fixture.ignored(&gitignore, "a.tmp", false));
+        assert!(fixture.ignored(&gitignore, "sub/b.tmp", false));
+        assert!(!fixture.ignored(&gitignore, "keep.tmp", false));
+        assert!(!fixture.ignored(&gitignore, "sub/keep.tmp", false));
+        assert!(!fixture.ignored(&gitignore, "a.txt", false));
+
+        // What's in an ignored directory can't be brought back.
+        assert!(fixture.ignored(&gitignore, "logs/keep.tmp", false));
+    }
+
+    #[test]
+    fn anchoring() {
+        let fixture = Fixture::new("anchoring", &[(".gitignore", "/build\ndoc/*.html\n")]);
+        let gitignore = GitIgnore::load(&fixture.0);
+
+        assert!(fixture.ignored(&gitignore, "build", true));
+        assert!(fixture.ignored(&gitignore, "build/out.o", false));
+        assert!(!fixture.ignored(&gitignore, "sub/build", true));
+        assert!(fixture.ignored(&gitignore, "doc/index.html", false));
+        assert!(!fixture.ignored(&gitignore, "doc/deep/index.html", false));
+        assert!(!fixture.ignored(&gitignore, "sub/doc/index.html", false));
+    }
+
+    #[test]
+    fn deeper_files_win() {
+        let fixture = Fixture::new(
+            "nested",
+            &[
+                (".gitignore", "*.log\n"),
+                ("sub/.gitignore", "!keep.log\n"),
+                ("other/.gitignore", "*.txt\n"),
+                ("other/.ignore", "!notes.txt\n"),
+            ],
+        );
+        let gitignore = GitIgnore::load(&fixture.0);
+
+        assert!(fixture.ignored(&gitignore, "keep.log", false));
+        assert!(!fixture.ignored(&gitignore, "sub/keep.log", false));
+        assert!(fixture.ignored(&gitignore, "sub/other.log", false));
+        // Rules only apply below their own directory.
+        assert!(!fixture.ignored(&gitignore, "a.txt", false));
+        assert!(fixture.ignored(&gitignore, "other/a.txt", false));
+        assert!(!fixture.ignored(&gitignore, "other/notes.txt", false));
+    }
+
+    #[test]
+    fn exclude_comments_and_git() {
+        let fixture = Fixture::new(
+            "exclude",
+            &[
+                (".git/info/exclude", "secret\n"),
+                (".gitignore", "# a comment\n\\#hash\n\n"),
+            ],
+        );
+        let gitignore = GitIgnore::load(&fixture.0);
+
+        assert!(fixture.ignored(&gitignore, "secret", false));
+        assert!(fixture.ignored(&gitignore, "#hash", false));
+        assert!(!fixture.ignored(&gitignore, "# a comment", false));
+        assert!(fixture.ignored(&gitignore, ".git/config", false));
+        assert!(GitIgnore::is_rule_file(&fixture.0.join("sub/.ignore")));
+        assert!(GitIgnore::is_rule_file(
+            &fixture.0.join(".git/info/exclude")
+        ));
+    }
+
+    #[test]
+    fn renames_need_both_sides_ignored() {
+        let fixture = Fixture::new("renames", &[(".gitignore", "*.swp\n")]);
+        let ignore = Ignore::new(vec![]).respect_gitignore(std::slice::from_ref(&fixture.0));
+        let renamed = |from: &str, to: &str| Event {
+            path: fixture.0.join(from).into(),
+            associated: Some(fixture.0.join(to).into()),
+            root: fixture.0.clone().into(),
+            what: What::Rename,
+            kind: Kind::File,
+            when: std::time::Duration::from_nanos(0),
+        };
+
+        assert!(!ignore.is_event_ignored(&renamed(".main.rs.swp", "main.rs")));
+        assert!(!ignore.is_event_ignored(&renamed("main.rs", ".main.rs.swp")));
+        assert!(ignore.is_event_ignored(&renamed("a.swp", "b.swp")));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::watcher::adapter::now;
+use crate::watcher::*;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::ops::Bound;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+//  What we last knew about a path.
+#[derive(Clone, Copy, PartialEq)]
+struct Entry {
+    kind: Kind,
+    ino: u64,
+    size: u64,
+    mtime: Duration,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl Entry {
+    fn of(meta: &fs::Metadata) -> Entry {
+        let kind = match meta.file_type() {
+            t if t.is_dir() => Kind::Dir,
+            t if t.is_symlink() => Kind::SymLink,
+            t if t.is_file() => Kind::File,
+            _ => Kind::Other,
+        };
+        Entry {
+            kind,
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default(),
+            mode: meta.mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+        }
+    }
+
+    fn is_replaced_by(&self, other: &Entry) -> bool {
+        self.kind != other.kind || self.ino != other.ino
+    }
+
+    //  A directory's times change with its contents,
+    //  which we report on their own.
+    fn is_modified_by(&self, other: &Entry) -> bool {
+        self.kind != Kind::Dir && (self.size != other.size || self.mtime != other.mtime)
+    }
+
+    fn is_owner_changed_by(&self, other: &Entry) -> bool {
+        self.mode != other.mode || self.uid != other.uid || self.gid != other.gid
+    }
+}
+
+/// Everything below a root, as of the last time we looked.
+/// Kept up to date with the events we send, so that when the kernel
+/// drops some, a rescan can tell what changed while it wasn't looking.
+pub struct Snapshot {
+    root: PathBuf,
+    options: WatchOptions,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Snapshot {
+    pub fn scan(root: &Path, options: &WatchOptions) -> Snapshot {
+        let mut snapshot = Snapshot {
+            root: root.to_path_buf(),
+            options: options.clone(),
+            entries: BTreeMap::new(),
+        };
+        snapshot.scan_below(root);
+        snapshot
+    }
+
+    //  Symlinks aren't followed, and excluded directories
+    //  are remembered, but not looked into.
+    fn scan_below(&mut self, dir: &Path) {
+        let mut dir_queue = VecDeque::<PathBuf>::from([dir.to_path_buf()]);
+
+        while let Some(dir) = dir_queue.pop_front() {
+            if let Ok(entries) = fs::read_dir(&dir) {
+                for dirent in entries.flatten() {
+                    let path = dirent.path();
+                    if let Ok(meta) = fs::symlink_metadata(&path) {
+                        if meta.is_dir() && !self.options.excludes(&path, &self.root) {
+                            dir_queue.push_back(path.clone());
+                        }
+                        self.entries.insert(path, Entry::of(&meta));
+                    }
+                }
+            }
+        }
+    }
+
+    //  Paths sort right before everything below them,
+    //  so a path and its contents are one range.
+    fn forget(&mut self, path: &Path) {
+        let gone: Vec<PathBuf> = self
+            .entries
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect();
+        for p in gone {
+            self.entries.remove(&p);
+        }
+    }
+
+    /// Brings what we know about the event's paths up to date.
+    pub fn update(&mut self, event: &Event) {
+        for path in std::iter::once(&event.path).chain(event.associated.as_ref()) {
+            if **path == *self.root || !path.starts_with(&self.root) {
+                continue;
+            }
+            match (event.what, fs::symlink_metadata(path)) {
+                (What::Modify | What::Owner, Ok(meta)) => {
+                    self.entries.insert(path.to_path_buf(), Entry::of(&meta));
+                }
+                (_, Ok(meta)) => {
+                    self.forget(path);
+                    self.entries.insert(path.to_path_buf(), Entry::of(&meta));
+                    if meta.is_dir() && !self.options.excludes(path, &self.root) {
+                        self.scan_below(path);
+                    }
+                }
+                (_, Err(_)) => self.forget(path),
+            }
+        }
+    }
+
+    /// Looks at the whole tree again, and returns events
+    /// for whatever changed since we last knew about it.
+    /// Destroyed paths come first, deepest first, then the rest.
+    pub fn rescan(&mut self) -> Vec<Event> {
+        let before = std::mem::take(&mut self.entries);
+        let root = self.root.clone();
+        self.scan_below(&root);
+
+        let when = now();
+        let event = |path: &Path, what: What, kind: Kind| Event {
+            path: path.into(),
+            associated: None,
+            root: root.as_path().into(),
+            what,
+            kind,
+            when,
+        };
+
+        let mut events = Vec::new();
+
+        for (path, old) in before.iter().rev() {
+            match self.entries.get(path) {
+                Some(new) if !old.is_replaced_by(new) => {}
+                _ => events.push(event(path, What::Destroy, old.kind)),
+            }
+        }
+
+        for (path, new) in self.entries.iter() {
+            match before.get(path) {
+                Some(old) if old.is_replaced_by(new) => {
+                    events.push(event(path, What::Create, new.kind))
+                }
+                Some(old) if old.is_modified_by(new) => {
+                    events.push(event(path, What::Modify, new.kind))
+                }
+                Some(old) if old.is_owner_changed_by(new) => {
+                    events.push(event(path, What::Owner, new.kind))
+                }
+                Some(_) => {}
+                None => events.push(event(path, What::Create, new.kind)),
+            }
+        }
+
+        events
+    }
+}
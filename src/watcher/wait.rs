@@ -0,0 +1,274 @@
+use crate::watcher::adapter::now;
+use crate::watcher::*;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::channel as sync_channel;
+use std::sync::mpsc::Receiver as SyncReceiver;
+use std::sync::mpsc::TryRecvError::Empty;
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio::time::timeout;
+
+//  The path can show up between our looking for it and our
+//  watch on its parent being in place, and whatever watches the
+//  parent can fail, so we look for it every so often as well.
+const RECHECK: Duration = Duration::from_millis(250);
+
+//  A root which goes away before it's marked leaves nothing to watch.
+//  That's worth waiting out, unless it keeps happening while the root
+//  is there, which is more likely about us than about the root.
+const RETRIES: usize = 3;
+
+fn kind_of(path: &Path) -> Kind {
+    match path.metadata() {
+        Ok(meta) if meta.is_dir() => Kind::Dir,
+        Ok(meta) if meta.is_file() => Kind::File,
+        _ => Kind::Other,
+    }
+}
+
+fn event_about(root: &Path, what: What, kind: Kind) -> Event {
+    Event {
+        path: root.into(),
+        associated: None,
+        root: root.into(),
+        what,
+        kind,
+        when: now(),
+    }
+}
+
+//  We're ready once the first adapter we start, on the root or
+//  on whatever is above it, is. Nobody is listening for the rest.
+fn first_ready(ready_tx: &mut Option<ReadySender>) -> ReadySender {
+    ready_tx.take().unwrap_or_else(|| oneshot::channel().0)
+}
+
+//  Whether the user still wants us watching. Once they've
+//  said no, that sticks, however many times we ask.
+struct Lifetime {
+    ctl_rx: SyncReceiver<bool>,
+    living: bool,
+}
+
+impl Lifetime {
+    fn is_living(&mut self) -> bool {
+        self.living &= match self.ctl_rx.try_recv() {
+            Err(Empty) => true,
+            Ok(false) => false,
+            Ok(true) => true,
+            Err(_) => false,
+        };
+        self.living
+    }
+}
+
+//  Watches the nearest directory above the root which exists,
+//  one level at a time, until the root does too.
+//  Returns whether it does, or whether we were stopped first.
+async fn until_exists(
+    root: &Path,
+    options: &WatchOptions,
+    event_tx: &EventSender,
+    ready_tx: &mut Option<ReadySender>,
+    lifetime: &mut Lifetime,
+) -> bool {
+    while !root.exists() {
+        if !lifetime.is_living() {
+            return false;
+        }
+
+        let ancestor = nearest_dir(root).to_path_buf();
+        let Some(next) = root
+            .strip_prefix(&ancestor)
+            .ok()
+            .and_then(|rest| rest.components().next())
+            .map(|name| ancestor.join(name))
+        else {
+            sleep(RECHECK).await;
+            continue;
+        };
+
+        //  Nothing in the ancestor's subdirectories matters.
+        let ancestor_options = WatchOptions {
+            exclude: vec![Glob::new("*")],
+            rescan: false,
+            initial_scan: false,
+            ..options.clone()
+        };
+        let (ancestor_tx, mut ancestor_rx) = unbounded_channel();
+        let (ctl_tx, ctl_rx) = sync_channel::<bool>();
+        let ancestor_ready_tx = first_ready(ready_tx);
+        let adapter = spawn_blocking(move || {
+            adapter::open(
+                ancestor,
+                &ancestor_options,
+                ancestor_tx,
+                ancestor_ready_tx,
+                ctl_rx,
+            )
+        });
+
+        loop {
+            match timeout(RECHECK, ancestor_rx.recv()).await {
+                //  It went away before it was marked. We'll look for
+                //  whichever ancestor is left once the adapter ends.
+                Ok(Some(Err(WatchError::NoWatches(path)))) if !path.exists() => {}
+                Ok(Some(Err(error))) => {
+                    let _s = event_tx.send(Err(error));
+                }
+                //  The ancestor went away, or couldn't be watched.
+                Ok(None) => {
+                    sleep(RECHECK).await;
+                    break;
+                }
+                _ => {}
+            }
+            if next.exists() || !lifetime.is_living() {
+                break;
+            }
+        }
+
+        let _s = ctl_tx.send(false);
+        let _r = adapter.await;
+    }
+
+    true
+}
+
+//  How watching the root while it existed went.
+enum Watched {
+    Ended(bool),
+    //  Nothing could be marked, most likely because it went away first.
+    Unmarked(WatchError),
+}
+
+//  Watches the root until it goes away, or until we're stopped.
+//  The adapter's last event is ours to send, so when the root goes
+//  away, we say that it was destroyed instead.
+//  A directory which has only just appeared may have been filled
+//  before it was marked, so what the marking walk finds in it was
+//  created as far as we're concerned.
+async fn while_exists(
+    root: &Path,
+    appeared: bool,
+    options: &WatchOptions,
+    event_tx: &EventSender,
+    ready_tx: &mut Option<ReadySender>,
+    lifetime: &mut Lifetime,
+) -> Watched {
+    let is_dir = root.is_dir();
+    let is_filled = appeared && is_dir;
+    let root_options = WatchOptions {
+        initial_scan: options.initial_scan || is_filled,
+        ..options.clone()
+    };
+    let (root_tx, mut root_rx) = unbounded_channel();
+    let (ctl_tx, ctl_rx) = sync_channel::<bool>();
+    let workers = open(
+        root.to_path_buf(),
+        &root_options,
+        &root_tx,
+        first_ready(ready_tx),
+        ctl_rx,
+    );
+    drop(root_tx);
+    let mut unmarked = None;
+
+    loop {
+        match timeout(RECHECK, root_rx.recv()).await {
+            Ok(Some(Err(error @ WatchError::NoWatches(_)))) => unmarked = Some(error),
+            Ok(Some(Ok(event))) if event.is_last() => {
+                if is_dir && lifetime.is_living() {
+                    let _s = event_tx.send(Ok(event_about(root, What::Destroy, Kind::Dir)));
+                }
+            }
+            Ok(Some(Ok(event))) if is_filled && event.what == What::Exists => {
+                let _s = event_tx.send(Ok(Event {
+                    what: What::Create,
+                    ..event
+                }));
+            }
+            Ok(Some(next)) => {
+                let _s = event_tx.send(next);
+            }
+            Ok(None) => break,
+            Err(_elapsed) => {}
+        }
+        if !lifetime.is_living() {
+            let _s = ctl_tx.send(false);
+        }
+    }
+
+    let mut ok = true;
+    for worker in workers {
+        ok &= worker.await.unwrap_or(false);
+    }
+    match unmarked {
+        Some(error) => Watched::Unmarked(error),
+        None => Watched::Ended(ok),
+    }
+}
+
+/// Watches a root which might not exist yet, or might go away.
+/// While it's missing, we wait for it. When it appears, we send
+/// a create for it, and watch it until it goes away again.
+/// The last event is the watcher's own destroy, once we're stopped.
+pub async fn wait(
+    root: PathBuf,
+    options: WatchOptions,
+    event_tx: EventSender,
+    ready_tx: ReadySender,
+    ctl_rx: SyncReceiver<bool>,
+) -> bool {
+    let mut ready_tx = Some(ready_tx);
+    let mut lifetime = Lifetime {
+        ctl_rx,
+        living: true,
+    };
+    let mut existed = root.exists();
+    let mut ok = true;
+    let mut retries = 0;
+
+    while lifetime.is_living() {
+        if !existed {
+            if !until_exists(&root, &options, &event_tx, &mut ready_tx, &mut lifetime).await {
+                break;
+            }
+            let _s = event_tx.send(Ok(event_about(&root, What::Create, kind_of(&root))));
+        }
+
+        //  The adapters end on their own when the root goes away,
+        //  or when they can't watch it, which waiting won't fix,
+        //  unless it went away before they could.
+        match while_exists(
+            &root,
+            !existed,
+            &options,
+            &event_tx,
+            &mut ready_tx,
+            &mut lifetime,
+        )
+        .await
+        {
+            Watched::Unmarked(error) if root.exists() && retries == RETRIES => {
+                let _s = event_tx.send(Err(error));
+                ok = false;
+                break;
+            }
+            Watched::Unmarked(_) => retries += root.exists() as usize,
+            Watched::Ended(true) => retries = 0,
+            Watched::Ended(false) => {
+                ok = false;
+                break;
+            }
+        }
+        existed = false;
+    }
+
+    let _s = event_tx.send(Ok(event_about(&root, What::Destroy, Kind::Watcher)));
+    ok
+}
@@ -1,41 +1,162 @@
 use crate::watcher::*;
+use core::future::Future;
 use core::pin::Pin;
 use core::task::Context;
 use core::task::Poll;
-use core::time::Duration;
-use std::cmp::Ordering;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::mpsc::channel as sync_channel;
 use std::sync::mpsc::Receiver as SyncReceiver;
 use std::sync::mpsc::Sender as SyncSender;
-use std::sync::mpsc::TryRecvError;
-use tokio::task::spawn;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::sync::watch as watch_channel;
 use tokio::task::spawn_blocking;
-use tokio::time::sleep;
+use tokio::task::JoinHandle;
 use tokio_stream::Stream;
 
+/// Where the adapters send their events, and their errors.
+/// Sending never blocks, and wakes whoever is polling the stream.
+pub type EventSender = UnboundedSender<Result<Event, WatchError>>;
+
+/// Where an adapter says how many directories it marked, and when
+/// it was done, once its first marks are in place. An adapter which
+/// gives up before then drops it instead.
+pub type ReadySender = oneshot::Sender<(usize, Instant)>;
+
+/// How the first marks went, once every root has them in place.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Ready {
+    /// Directories marked, across every root.
+    pub dirs: usize,
+    /// From when we started watching until the last root was marked.
+    pub elapsed: Duration,
+}
+
+/// What watches the filesystem for us.
+/// `Auto` picks fanotify when we have what it needs,
+/// and falls back to inotify when we don't.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Backend {
+    #[default]
+    Auto,
+    Fanotify,
+    Inotify,
+}
+
+impl<'a> From<&'a str> for Backend {
+    fn from(s: &'a str) -> Backend {
+        match s {
+            "fanotify" => Backend::Fanotify,
+            "inotify" => Backend::Inotify,
+            _ => Backend::Auto,
+        }
+    }
+}
+
+impl From<String> for Backend {
+    fn from(s: String) -> Backend {
+        Backend::from(s.as_str())
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Backend::Auto => write!(f, "auto"),
+            Backend::Fanotify => write!(f, "fanotify"),
+            Backend::Inotify => write!(f, "inotify"),
+        }
+    }
+}
+
+/// How the adapters should watch each path.
+#[derive(Clone, Debug, Default)]
+pub struct WatchOptions {
+    /// Directories matching these aren't watched, and neither is anything below them.
+    /// They match like `Ignore`'s globs do.
+    pub exclude: Vec<Glob>,
+    pub backend: Backend,
+    /// When the kernel drops events, look at the tree again
+    /// and send events for whatever changed in the meantime.
+    /// Costs a walk of the tree up front, and memory for what it saw.
+    pub rescan: bool,
+    /// When a root doesn't exist, wait for it to be created instead of failing,
+    /// and when it goes away, wait for it to come back instead of ending.
+    pub wait: bool,
+    /// Once a root's marks are in place, send an `Exists` event
+    /// for everything below it, before any other events.
+    pub initial_scan: bool,
+}
+
+impl WatchOptions {
+    /// Whether the adapters should leave this directory, and what's below it, alone.
+    /// The root itself is always watched.
+    pub fn excludes(&self, dir: &Path, root: &Path) -> bool {
+        dir != root && is_excluded(&self.exclude, dir, root)
+    }
+}
+
 #[derive(PartialEq)]
 enum State {
     Open,
     Closed,
 }
 
+/// Events from every watched path, merged in the order they arrive.
+/// The stream ends once every path's adapter is done.
 pub struct EventStream {
     state: State,
-    worker: tokio::task::JoinHandle<bool>,
-    waker: Option<tokio::task::JoinHandle<()>>,
-    delay: Delay,
-    ctl_tx: SyncSender<bool>,
-    event_rx: SyncReceiver<Event>,
+    ctl_txs: Vec<SyncSender<bool>>,
+    roots: Vec<PathBuf>,
+    backends: Vec<Backend>,
+    ready_rx: watch_channel::Receiver<Option<Ready>>,
+    event_rx: UnboundedReceiver<Result<Event, WatchError>>,
 }
 
 impl EventStream {
+    /// The canonical form of each path we're watching.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// The backend watching each root, in the same order as `roots`.
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    /// Resolves once every root's first marks are in place,
+    /// so that changes made after it are seen.
+    /// It doesn't borrow the stream, so it can be awaited beside it.
+    pub fn ready(&self) -> impl Future<Output = Ready> + 'static {
+        let mut ready_rx = self.ready_rx.clone();
+        async move {
+            match ready_rx.wait_for(Option::is_some).await {
+                Ok(ready) => ready.unwrap_or_default(),
+                Err(_) => Ready::default(),
+            }
+        }
+    }
+
+    /// Holds each path's events back until it has been quiet for
+    /// `window`, and sends what its burst of them came down to.
+    pub fn debounce(self, window: Duration) -> Debounced {
+        Debounced::new(self, window)
+    }
+
+    /// Asks every adapter to stop. The stream goes on with whatever
+    /// they send on their way out, ending with their last events.
     pub fn stop(&mut self) -> bool {
         match self.state {
             State::Open => {
                 self.state = State::Closed;
-                let ret = self.ctl_tx.send(false).is_ok();
-                if !self.worker.is_finished() {
-                    self.worker.abort();
+                let mut ret = true;
+                for ctl_tx in &self.ctl_txs {
+                    ret &= ctl_tx.send(false).is_ok();
                 }
                 ret
             }
@@ -44,170 +165,150 @@ impl EventStream {
     }
 }
 
-struct Delay {
-    idx: usize,
-}
-
-impl Delay {
-    const fn ms(d: u64) -> Duration {
-        Duration::from_millis(d)
-    }
+impl Stream for EventStream {
+    type Item = Result<Event, WatchError>;
 
-    const PROGRESSION: &[Duration] = &[
-        // 16 x 16 ms = 256 ms
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        Delay::ms(16),
-        // 8 x 32 ms = 256 ms
-        Delay::ms(32),
-        Delay::ms(32),
-        Delay::ms(32),
-        Delay::ms(32),
-        Delay::ms(32),
-        Delay::ms(32),
-        // 8 x 64 ms = 512 ms
-        Delay::ms(64),
-        Delay::ms(64),
-        Delay::ms(64),
-        Delay::ms(64),
-        Delay::ms(64),
-        Delay::ms(64),
-        Delay::ms(64),
-        Delay::ms(64),
-        // 4 x 128 = 512 ms
-        Delay::ms(128),
-        Delay::ms(128),
-        Delay::ms(128),
-        Delay::ms(128),
-        // 2 x 256 ms = 512 ms
-        Delay::ms(256),
-        Delay::ms(256),
-        // 1 x 512 ms = 512 ms
-        Delay::ms(512),
-        // We don't grow beyond the last delay
-    ];
-
-    pub fn new() -> Delay {
-        Delay { idx: 0 }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        /*  The channel registers our waker when it's empty,
+         *  and the adapter's next send wakes us right away.
+         *  When the adapters are gone, the channel is closed,
+         *  whether or not we were stopped. */
+        self.event_rx.poll_recv(cx)
     }
+}
 
-    // pub fn as_duration(&self) -> &Duration {
-    //     Delay::PROGRESSION.get(self.idx).unwrap()
-    // }
+//  Event paths are reported below the canonical root, so relative
+//  roots and symlinks line up with them. For a path which doesn't
+//  exist yet, that's as much of it as does.
+fn canonical(path: PathBuf) -> PathBuf {
+    let path = std::path::absolute(&path).unwrap_or(path);
+    path.ancestors()
+        .find_map(|a| {
+            let canonical_a = std::fs::canonicalize(a).ok()?;
+            let rest = path.strip_prefix(a).ok()?;
+            Some(match rest.as_os_str().is_empty() {
+                true => canonical_a,
+                false => canonical_a.join(rest),
+            })
+        })
+        .unwrap_or(path)
+}
 
-    pub fn to_duration(&self) -> Duration {
-        *Delay::PROGRESSION.get(self.idx).unwrap()
-    }
+//  The directory an adapter would watch for this path:
+//  the path itself, or the nearest directory above it.
+pub(crate) fn nearest_dir(path: &Path) -> &Path {
+    path.ancestors().find(|a| a.is_dir()).unwrap_or(path)
+}
 
-    pub fn initial_delay(&self) -> Delay {
-        Delay { idx: 0 }
-    }
+/// Starts watching a root which exists, and returns what's watching it.
+/// A file is watched through its parent directory, so
+/// that we can follow it when it's replaced by a rename.
+pub(crate) fn open(
+    root: PathBuf,
+    options: &WatchOptions,
+    event_tx: &EventSender,
+    ready_tx: ReadySender,
+    ctl_rx: SyncReceiver<bool>,
+) -> Vec<JoinHandle<bool>> {
+    let is_file = root.metadata().is_ok_and(|meta| !meta.is_dir());
+    let watched = match (is_file, root.parent()) {
+        (true, Some(parent)) => parent.to_path_buf(),
+        _ => root.clone(),
+    };
+    let mut options = options.clone();
+    let mut workers = Vec::with_capacity(2);
 
-    pub fn reset(&mut self) {
-        *self = self.initial_delay();
-    }
+    let adapter_tx = match watched != root {
+        true => {
+            //  Nothing in the parent's subdirectories matters.
+            options.exclude = vec![Glob::new("*")];
+            let (file_tx, file_rx) = unbounded_channel();
+            workers.push(tokio::spawn(file::follow(root, file_rx, event_tx.clone())));
+            file_tx
+        }
+        false => event_tx.clone(),
+    };
 
-    pub fn next(&self) -> Delay {
-        let clamp_next_idx = match self.idx.cmp(&(Delay::PROGRESSION.len() - 1)) {
-            Ordering::Less => self.idx + 1,
-            _ => self.idx,
-        };
+    workers.push(spawn_blocking(move || {
+        adapter::open(watched, &options, adapter_tx, ready_tx, ctl_rx)
+    }));
+    workers
+}
 
-        Delay {
-            idx: clamp_next_idx,
+//  Waits to hear from every root's adapter, then tells
+//  whoever is waiting on the stream that it's ready.
+async fn gather_ready(
+    started: Instant,
+    ready_rxs: Vec<oneshot::Receiver<(usize, Instant)>>,
+    ready_tx: watch_channel::Sender<Option<Ready>>,
+) {
+    let mut ready = Ready::default();
+    let mut done = started;
+    for ready_rx in ready_rxs {
+        if let Ok((dirs, at)) = ready_rx.await {
+            ready.dirs += dirs;
+            done = done.max(at);
         }
     }
+    ready.elapsed = done.duration_since(started);
+    let _s = ready_tx.send(Some(ready));
 }
 
-impl Stream for EventStream {
-    type Item = Event;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let is_chan_ok = |e: TryRecvError| matches!(e, TryRecvError::Empty);
-
-        if self.state == State::Open {
-            // println!(
-            //     "idx: {} , delay: {} ms",
-            //     self.delay.idx,
-            //     self.delay.as_duration().as_millis()
-            // );
-            match self.event_rx.try_recv() {
-                /*  We have an event ready now. Send it. */
-                Ok(event) => {
-                    self.delay.reset();
-                    Poll::Ready(Some(event))
-                }
-
-                Err(e) => {
-                    /*  Nothing received just yet. */
-                    if is_chan_ok(e) {
-                        /*  The channel is alive.
-                         *  Progress our `delay.next()`
-                         *  and schedule a wake for ourselves. */
-                        self.waker
-                            .take()
-                            .map(|waker| waker.is_finished())
-                            .unwrap_or(true)
-                            .then(|| {
-                                self.delay = self.delay.next();
-                                self.waker = Some(spawn(wake_after(
-                                    cx.waker().clone(),
-                                    self.delay.to_duration(),
-                                )))
-                            });
-
-                        Poll::Pending
-                    } else {
-                        /*  Channel was closed... Or something bad. */
-                        Poll::Ready(None)
-                    }
-                }
-            }
-        } else {
-            /*  We are dead. */
-            Poll::Ready(None)
-        }
-    }
+pub fn watch(path: impl Into<PathBuf>) -> EventStream {
+    watch_many(vec![path])
 }
 
-async fn wake_after(waker: std::task::Waker, delay: Duration) {
-    sleep(delay).await;
-    waker.wake_by_ref();
+pub fn watch_many(paths: Vec<impl Into<PathBuf>>) -> EventStream {
+    watch_with(paths, WatchOptions::default())
 }
 
-pub fn watch(path: String) -> EventStream {
+pub fn watch_with(paths: Vec<impl Into<PathBuf>>, options: WatchOptions) -> EventStream {
     use State::Open;
 
-    let canonical_path: String = path.clone();
-    // std::path::PathBuf::from(&path)
-    // .canonicalize()
-    // .unwrap()
-    // .to_string_lossy()
-    // .into_owned();
+    let started = Instant::now();
+    let (event_tx, event_rx) = unbounded_channel();
+
+    let mut ready_rxs = Vec::with_capacity(paths.len());
+    let mut ctl_txs = Vec::with_capacity(paths.len());
+    let mut roots = Vec::with_capacity(paths.len());
+    let mut backends = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let canonical_path = canonical(path.into());
+        let options = WatchOptions {
+            backend: adapter::resolve(nearest_dir(&canonical_path), options.backend),
+            ..options.clone()
+        };
 
-    let (ctl_tx, ctl_rx) = sync_channel::<bool>();
+        roots.push(canonical_path.clone());
+        backends.push(options.backend);
+
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (ctl_tx, ctl_rx) = sync_channel::<bool>();
+        match options.wait {
+            //  The tasks end once their adapters do.
+            true => drop(tokio::spawn(wait::wait(
+                canonical_path,
+                options,
+                event_tx.clone(),
+                ready_tx,
+                ctl_rx,
+            ))),
+            false => drop(open(canonical_path, &options, &event_tx, ready_tx, ctl_rx)),
+        }
+        ready_rxs.push(ready_rx);
+        ctl_txs.push(ctl_tx);
+    }
 
-    let (event_tx, event_rx) = sync_channel::<Event>();
+    let (ready_tx, ready_rx) = watch_channel::channel(None);
+    drop(tokio::spawn(gather_ready(started, ready_rxs, ready_tx)));
 
     EventStream {
         state: Open,
-        waker: None,
-        worker: spawn_blocking(move || adapter::open(canonical_path, event_tx, ctl_rx)),
-        delay: Delay::new(),
-        ctl_tx,
+        ctl_txs,
+        roots,
+        backends,
+        ready_rx,
         event_rx,
     }
 }